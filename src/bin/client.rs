@@ -1,49 +1,121 @@
 use anyhow::Error;
 use blobfish::{
-    client::Offer,
+    client::{Connected, Fetch, Offer},
     client_args::{Cli, Commands},
-    protocol::{MyPkg, Piece, BLOCK_SIZE},
-    Client,
+    protocol::{FetchMyPkg, Hello, ListMyPkg, MyPkg},
+    tls::ClientTls,
+    transport, Client,
 };
+use chrono::DateTime;
 use clap::Parser;
-use serde_bytes::ByteBuf;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     let args = Cli::parse();
+    let tls = args.tls()?;
 
     match args.command {
-        Commands::Upload { name, file } => {
-            let mypkg = MyPkg::new(name, file).unwrap();
-            let mut state = Offer::new(Client::open(args.connect_to).await?)
+        Commands::Upload {
+            name,
+            file,
+            tag,
+            digest,
+            compress,
+        } => {
+            let mut mypkg = MyPkg::new(name, file, digest)?;
+            mypkg.tags = tag;
+            let mut state = Offer::new(open(args.connect_to, &args.exec, &tls).await?)
                 .offer(mypkg.clone())
                 .await?
                 .add_peers(vec!["127.0.0.1:2040".into()])
+                .compression(compress.as_slice())
                 .negotiate()
                 .await?;
-            // tricky, how do i do multiple files? i need a loop or something
-            let mut buf: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
-            for file in mypkg.files {
-                dbg!(&file);
-                let piece_count = file.clone().chunk_count();
-                println!("send file {} piece_count {}", file.path, piece_count);
-                state
-                    .exchange([0, piece_count as u64], file.clone())
-                    .await?;
-                let read_at = file.read_at()?;
-                for piece in 0..piece_count {
-                    read_at(piece as u64, &mut buf)?;
-                    let p = Piece {
-                        piece: piece as u64,
-                        ack: None,
-                        data: ByteBuf::from(buf),
-                    };
-                    state.send(p).await?
-                }
+            for file in state.wanted() {
+                state.send_file(file).await?;
             }
+            state.done().await?;
+            Ok(())
+        }
+        Commands::Download {
+            package,
+            output,
+            compress,
+        } => {
+            let mut state = Fetch::new(open(args.connect_to, &args.exec, &tls).await?)
+                .fetch(FetchMyPkg::new(package).with_compression(compress.as_slice()))
+                .await?;
+            let mypkg = state.mypkg().clone();
+            println!("download {} {}", mypkg.name, mypkg.md5sum);
+            for file in state.receive(&output).await? {
+                println!("{} {}", file.md5sum, file.path);
+            }
+            Ok(())
+        }
+        Commands::List {
+            name,
+            tag,
+            offset,
+            limit,
+            json,
+        } => {
+            let query = ListMyPkg {
+                name,
+                tags: tag,
+                offset,
+                limit,
+            };
+            let ack = open(args.connect_to, &args.exec, &tls)
+                .await?
+                .list(query)
+                .await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&ack.mypkgs)?);
+                return Ok(());
+            }
+            println!(
+                "{:<24} {:<64} {:<12} {:<20} {:<16} {:<5} TAGS",
+                "NAME", "SUM", "AUTHOR", "BUILT_ON", "OS/ARCH", "FILES"
+            );
+            for mypkg in &ack.mypkgs {
+                let built_on = DateTime::from_timestamp_millis(mypkg.built_on)
+                    .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+                    .unwrap_or_default();
+                println!(
+                    "{:<24} {:<64} {:<12} {:<20} {:<16} {:<5} {}",
+                    mypkg.name,
+                    mypkg.md5sum,
+                    mypkg.author,
+                    built_on,
+                    format!("{}/{}", mypkg.os, mypkg.arch),
+                    mypkg.files.len(),
+                    mypkg.tags.join(",")
+                );
+            }
+            println!(
+                "showing {} of {} packages from offset {}",
+                ack.mypkgs.len(),
+                ack.total,
+                offset
+            );
             Ok(())
         }
-        Commands::Download { file: _ } => Ok(()),
-        Commands::List => Ok(()),
     }
 }
+
+// reach the server at addr, or through a spawned command such as ssh when exec is given
+async fn open(
+    addr: String,
+    exec: &Option<String>,
+    tls: &Option<ClientTls>,
+) -> Result<Client<Connected>, Error> {
+    let stream = match exec {
+        Some(command) => transport::spawn(command)?,
+        None => transport::connect(&addr).await?,
+    };
+    let stream = match tls {
+        Some(tls) => tls.connect(stream).await?,
+        None => stream,
+    };
+    Client::handshake(stream, Hello::new()).await
+}
@@ -1,15 +1,22 @@
-use blobfish::Server;
+use blobfish::{server_args::ServerArgs, transport, Server};
+use clap::Parser;
 use std::collections::HashSet;
-use tokio::net::TcpListener;
 use tokio_util::sync::CancellationToken;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind("127.0.0.1:8080").await?;
+    let args = ServerArgs::parse();
+    if args.stdio {
+        Server::serve_stream(transport::stdio(), HashSet::new()).await?;
+        return Ok(());
+    }
+    let tls = args.tls()?;
+    let listener = transport::bind(&args.listen).await?;
     let token = CancellationToken::new();
-    Server::new(listener, HashSet::new())
-        .await?
-        .serve(token.clone())
-        .await?;
+    let mut server = Server::new(listener, HashSet::new()).await?;
+    if let Some(tls) = tls {
+        server = server.tls(tls);
+    }
+    server.serve(token.clone()).await?;
     Ok(())
 }
@@ -1,16 +1,17 @@
 use crate::protocol::{
-    MessageType, ToMessageType, BLOCK_SIZE, BLOCK_SIZE_LESS_HEADER, HEADER_SIZE, MSG_SIZE,
+    Codec, ErrorMsg, Hello, HelloAck, ListMyPkg, ListMyPkgAck, MessageType, Piece, ReadResult,
+    ToMessageType,
 };
-use anyhow::{bail, Result};
-
+use crate::tls::ClientTls;
+use crate::transport::{self, Stream};
+use anyhow::{bail, Error, Result};
+use futures::{SinkExt, StreamExt};
 use serde::de;
+use std::io;
+use tokio::io::AsyncWriteExt;
+use tokio_util::codec::Framed;
 
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
-
-pub struct ClientConnection(TcpStream);
+pub struct ClientConnection(Framed<Stream, Codec>);
 pub struct Client<S: ClientState> {
     // state is our marker
     // conn is available on all states and should be exposed as &mut self for read/writes
@@ -20,7 +21,10 @@ pub struct Client<S: ClientState> {
 }
 
 pub trait ClientState {}
-pub struct Connected;
+pub struct Connected {
+    // what the server answered to our hello
+    hello: HelloAck,
+}
 pub struct Disconnected;
 
 impl ClientState for Connected {}
@@ -28,66 +32,46 @@ impl ClientState for Disconnected {}
 
 impl Client<Disconnected> {
     pub async fn open(addr: String) -> Result<Client<Connected>> {
+        Self::connect(addr, Hello::new()).await
+    }
+    // connect and say hello, a server speaking an incompatible version is refused.
+    // addr is a tcp address or unix:<path> for a unix socket
+    pub async fn connect(addr: String, hello: Hello) -> Result<Client<Connected>> {
+        Self::handshake(transport::connect(&addr).await?, hello).await
+    }
+    // connect over tls, the server has to prove who it is before we say hello
+    pub async fn open_tls(addr: String, tls: &ClientTls) -> Result<Client<Connected>> {
+        let stream = tls.connect(transport::connect(&addr).await?).await?;
+        Self::handshake(stream, Hello::new()).await
+    }
+    // say hello over a connection that is already open, such as one end of a pipe
+    pub async fn handshake(stream: Stream, hello: Hello) -> Result<Client<Connected>> {
+        let mut client = Client {
+            inner: ClientConnection(Framed::new(stream, Codec::default())),
+            state: Disconnected,
+        };
+        client
+            .write_message_type(&MessageType::Hello(hello.clone()))
+            .await?;
+        let ack: HelloAck = client.read().await?;
+        hello.check_version(ack.version, ack.min_version)?;
         Ok(Client {
-            inner: ClientConnection(TcpStream::connect(addr).await?),
-            state: Connected,
+            inner: client.inner,
+            state: Connected { hello: ack },
         })
     }
 }
-pub struct ReadResult {
-    pub message_type: u16,
-    pub raw_msg: Vec<u8>,
-}
-
-impl Client<Connected> {
+impl<S: ClientState> Client<S> {
     pub async fn close(&mut self) -> Result<()> {
-        self.inner.0.shutdown().await.map_err(anyhow::Error::from)
+        self.inner.0.get_mut().shutdown().await.map_err(Error::from)
     }
     pub async fn write_message_type(&mut self, t: &MessageType) -> Result<()> {
-        let b = t.serialize_inner()?;
-        let message_type = t.message_type();
-        let mut buf = [0; BLOCK_SIZE];
-        for chunk in b.chunks(BLOCK_SIZE_LESS_HEADER) {
-            let length = chunk.len() as u16;
-            buf[0..MSG_SIZE].copy_from_slice(&length.to_be_bytes());
-            buf[MSG_SIZE..HEADER_SIZE].copy_from_slice(&message_type.to_be_bytes());
-            buf[HEADER_SIZE..HEADER_SIZE + chunk.len()].copy_from_slice(chunk);
-            self.inner
-                .0
-                .write_all(&buf[..HEADER_SIZE + length as usize])
-                .await?;
-        }
-        Ok(())
+        self.inner.0.send(t).await
     }
     pub async fn read_message_type(&mut self) -> Result<ReadResult> {
-        let mut buf = [0; BLOCK_SIZE];
-        let mut raw_msg = vec![];
-        loop {
-            self.inner.0.read_exact(&mut buf[..HEADER_SIZE]).await?;
-            let prefix_length = u16::from_be_bytes([buf[0], buf[1]]) as usize;
-            if prefix_length > BLOCK_SIZE_LESS_HEADER {
-                bail!("invalid frame length {}", prefix_length);
-            }
-            let message_type = u16::from_be_bytes([buf[2], buf[3]]);
-            if !MessageType::is_valid_message_type(message_type) {
-                bail!("invalid message type {}", message_type);
-            }
-            let n = self
-                .inner
-                .0
-                .read_exact(&mut buf[HEADER_SIZE..HEADER_SIZE + prefix_length])
-                .await?;
-
-            if n > 0 {
-                raw_msg.extend_from_slice(&buf[HEADER_SIZE..HEADER_SIZE + n]);
-            }
-            if prefix_length < BLOCK_SIZE_LESS_HEADER {
-                // return MessageType::deserialize(message_type, &raw_msg);
-                return Ok(ReadResult {
-                    message_type,
-                    raw_msg,
-                });
-            }
+        match self.inner.0.next().await {
+            Some(r) => r,
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
         }
     }
     pub async fn read<T: de::DeserializeOwned>(&mut self) -> Result<T> {
@@ -95,7 +79,42 @@ impl Client<Connected> {
         let msg: T = serde_bencode::from_bytes(&r.raw_msg).map_err(|e| dbg!(e))?;
         Ok(msg)
     }
+    // pieces come in their own binary frame, not bencoded
+    pub async fn read_piece(&mut self) -> Result<Piece> {
+        let r = self.read_message_type().await?;
+        if r.message_type != MessageType::PIECE {
+            bail!(ErrorMsg::new(
+                ErrorMsg::UNEXPECTED,
+                format!("expected a piece but got message type {}", r.message_type)
+            ));
+        }
+        Piece::decode(r.raw_msg)
+    }
     pub async fn write<T: ToMessageType>(&mut self, t: T) -> Result<()> {
         self.write_message_type(&t.to_message_type()).await
     }
+    // tell the server why we are giving up on the session, the error is handed back
+    pub async fn report(&mut self, e: Error) -> Error {
+        if let Some(msg) = ErrorMsg::from_error(&e) {
+            // the server may be gone already, there is no one left to tell then
+            let _ = self.write(msg).await;
+        }
+        e
+    }
+}
+
+impl Client<Connected> {
+    // the protocol version both sides agreed on
+    pub fn version(&self) -> u64 {
+        self.state.hello.version
+    }
+    // the optional features both sides support
+    pub fn features(&self) -> &[String] {
+        &self.state.hello.features
+    }
+    // ask the server for one page of its package catalog
+    pub async fn list(&mut self, query: ListMyPkg) -> Result<ListMyPkgAck> {
+        self.write(query).await?;
+        self.read().await
+    }
 }
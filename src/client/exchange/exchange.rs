@@ -1,8 +1,8 @@
-use anyhow::{Error, Result};
+use anyhow::{bail, Error, Result};
 
 use crate::{
     client::Connected,
-    protocol::{File, Piece, PieceExchange, PieceExchangeAck},
+    protocol::{Compression, Done, File, MyPkg, MyPkgAck, Piece, PieceExchange, PieceExchangeAck},
     Client,
 };
 
@@ -12,19 +12,86 @@ pub struct Exchange<S: ExchangeState> {
 }
 
 pub trait ExchangeState {}
-pub struct Ready;
+pub struct Ready {
+    pub mypkg: MyPkg,
+    pub ack: MyPkgAck,
+    // what the server agreed to have pieces compressed with
+    pub compression: Option<Compression>,
+}
 
 impl ExchangeState for Ready {}
 
 impl Exchange<Ready> {
-    pub async fn exchange(&mut self, pieces: [u64; 2], file: File) -> Result<(), Error> {
+    // the files the server asked for, it already holds the rest
+    pub fn wanted(&self) -> Vec<File> {
+        self.state
+            .ack
+            .files
+            .clone()
+            .unwrap_or(self.state.mypkg.files.clone())
+    }
+    // offer the pieces of a file, returns the ranges the server still wants
+    pub async fn exchange(&mut self, pieces: [u64; 2], file: File) -> Result<Vec<[u64; 2]>, Error> {
         let pe = PieceExchange { pieces, file };
         self.inner.write(pe).await?;
         let pa: PieceExchangeAck = self.inner.read().await?;
-        dbg!(pa);
+        dbg!(&pa);
+        // an empty file offers an empty range, there is nothing to send for it
+        let wanted = pa.pieces.unwrap_or(vec![pieces]);
+        Ok(wanted
+            .into_iter()
+            .filter(|[start, end]| start < end)
+            .collect())
+    }
+    // the ranges the server still misses after a round of pieces, empty once the file is whole
+    pub async fn missing(&mut self) -> Result<Vec<[u64; 2]>, Error> {
+        let pa: PieceExchangeAck = self.inner.read().await?;
+        Ok(pa.pieces.unwrap_or_default())
+    }
+    // offer a file and send every piece the server asks for, again if it arrived corrupt
+    pub async fn send_file(&mut self, file: File) -> Result<(), Error> {
+        match self.send_pieces(file).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(self.inner.report(e).await),
+        }
+    }
+    async fn send_pieces(&mut self, file: File) -> Result<(), Error> {
+        let piece_count = file.clone().chunk_count();
+        println!("send file {} piece_count {}", file.path, piece_count);
+        let mut wanted = self.exchange([0, piece_count as u64], file.clone()).await?;
+        let read_at = file.clone().read_at()?;
+        while !wanted.is_empty() {
+            for [start, end] in wanted {
+                for piece in start..end {
+                    let p =
+                        Piece::read(&read_at, &file, piece)?.compress(self.state.compression)?;
+                    self.send(p).await?
+                }
+            }
+            wanted = self.missing().await?;
+            if !wanted.is_empty() {
+                println!("server asked again for {:?}", &wanted);
+            }
+        }
         Ok(())
     }
     pub async fn send(&mut self, piece: Piece) -> Result<(), Error> {
         self.inner.write(piece).await
     }
+    // tell the server every file has been sent and wait for it to commit the package
+    pub async fn done(&mut self) -> Result<(), Error> {
+        let done = Done {
+            md5sum: self.state.mypkg.md5sum.to_owned(),
+        };
+        self.inner.write(done).await?;
+        let committed: Done = self.inner.read().await?;
+        if committed.md5sum != self.state.mypkg.md5sum {
+            bail!(
+                "peer committed {} but {} was sent",
+                committed.md5sum,
+                self.state.mypkg.md5sum
+            );
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,149 @@
+use anyhow::{bail, Error, Result};
+
+use crate::{
+    client::Connected,
+    protocol::{
+        hash_file, ranges, Compression, FetchMyPkg, FetchMyPkgAck, File, MyPkg, PieceExchange,
+        PieceExchangeAck, MAX_RETRANSMITS,
+    },
+    Client,
+};
+
+pub struct Fetch<S: FetchState> {
+    inner: Client<Connected>,
+    state: S,
+}
+
+pub trait FetchState {}
+pub struct FetchMsg;
+pub struct Receiving {
+    mypkg: MyPkg,
+    // what the server chose to compress pieces with
+    compression: Option<Compression>,
+}
+
+impl FetchState for FetchMsg {}
+impl FetchState for Receiving {}
+
+impl Fetch<FetchMsg> {
+    pub fn new(client: Client<Connected>) -> Fetch<FetchMsg> {
+        Fetch {
+            inner: client,
+            state: FetchMsg,
+        }
+    }
+    pub async fn fetch(mut self, query: FetchMyPkg) -> Result<Fetch<Receiving>, Error> {
+        self.inner.write(query.clone()).await?;
+        let ack: FetchMyPkgAck = self.inner.read().await?;
+        match ack.mypkg {
+            Some(mypkg) => Ok(Fetch {
+                inner: self.inner,
+                state: Receiving {
+                    mypkg,
+                    compression: ack.compression,
+                },
+            }),
+            None => bail!(
+                "peer does not have {}",
+                query.md5sum.or(query.name).unwrap_or_default()
+            ),
+        }
+    }
+}
+
+impl Fetch<Receiving> {
+    pub fn mypkg(&self) -> &MyPkg {
+        &self.state.mypkg
+    }
+    // receive every file of the package into dir, each file is verified against its sum
+    pub async fn receive(&mut self, dir: &str) -> Result<Vec<File>, Error> {
+        match self.receive_files(dir).await {
+            Ok(received) => Ok(received),
+            Err(e) => Err(self.inner.report(e).await),
+        }
+    }
+    async fn receive_files(&mut self, dir: &str) -> Result<Vec<File>, Error> {
+        let mut received = vec![];
+        for file in self.state.mypkg.files.clone() {
+            let pe: PieceExchange = self.inner.read().await?;
+            if pe.file.md5sum != file.md5sum {
+                bail!(
+                    "peer sent file {} but {} was expected",
+                    pe.file.md5sum,
+                    file.md5sum
+                );
+            }
+            self.inner.write(PieceExchangeAck { pieces: None }).await?;
+
+            let path = format!("{}/{}", dir, file.filename());
+            println!("receive file {} pieces {:?}", &path, pe.pieces);
+            // start from an empty file so a previous download can't leave trailing bytes
+            if let Some(parent) = std::path::Path::new(&path).parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::File::create(&path)?;
+            let mut write_at = file.clone().write_at(path.clone())?;
+            let mut wanted = vec![pe.pieces];
+            let mut rounds = 0;
+            while !wanted.is_empty() {
+                if rounds > MAX_RETRANSMITS {
+                    bail!(
+                        "{} still misses {:?} after {} rounds",
+                        &path,
+                        wanted,
+                        rounds
+                    );
+                }
+                let mut corrupt = vec![];
+                for [start, end] in wanted {
+                    for _i in start..end {
+                        let p = self.inner.read_piece().await?;
+                        if p.piece < start || p.piece >= end {
+                            bail!(
+                                "piece is out of bounds {} is not within {}:{}",
+                                p.piece,
+                                start,
+                                end
+                            );
+                        }
+                        let len = file.piece_len(p.piece);
+                        let p = p.decompress(self.state.compression, len)?;
+                        if p.data.len() != file.piece_len(p.piece) {
+                            bail!(
+                                "piece {} is {} bytes but should be {}",
+                                p.piece,
+                                p.data.len(),
+                                file.piece_len(p.piece)
+                            );
+                        }
+                        if !file.verify_piece(p.piece, &p.data) {
+                            println!("piece {} of {} is corrupt, asking again", p.piece, &path);
+                            corrupt.push(p.piece);
+                            continue;
+                        }
+                        write_at(p.piece, &p.data)?;
+                    }
+                }
+                corrupt.sort();
+                wanted = ranges(corrupt);
+                let pa = PieceExchangeAck {
+                    pieces: Some(wanted.clone()),
+                };
+                self.inner.write(pa).await?;
+                rounds += 1;
+            }
+
+            let written = hash_file(&path, file.digest)?;
+            if written.md5sum != file.md5sum || written.length != file.length {
+                bail!(
+                    "downloaded {} does not match, expected {} got {}",
+                    &path,
+                    file.md5sum,
+                    written.md5sum
+                );
+            }
+            received.push(written);
+        }
+        Ok(received)
+    }
+}
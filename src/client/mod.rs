@@ -1,6 +1,8 @@
 pub mod client;
 pub mod exchange;
+pub mod fetch;
 pub mod offer;
 
 pub use client::Connected;
+pub use fetch::fetch::Fetch;
 pub use offer::offer::Offer;
@@ -3,7 +3,7 @@ use std::{borrow::BorrowMut, collections::HashSet};
 use crate::{
     client::exchange::{Exchange, Ready},
     client::Connected,
-    protocol::{MyPkg, MyPkgAck, NegotiateMyPkg, NegotiateMyPkgAck},
+    protocol::{Compression, MyPkg, MyPkgAck, NegotiateMyPkg, NegotiateMyPkgAck},
     Client,
 };
 use anyhow::{bail, Error};
@@ -20,6 +20,8 @@ pub struct Negotiate {
     ack: MyPkgAck,
 
     peers: HashSet<String>,
+    // the compressions we offer to send pieces with, most preferred first
+    compression: Vec<Compression>,
 }
 
 impl Negotiate {
@@ -28,6 +30,7 @@ impl Negotiate {
             mypkg,
             ack,
             peers: HashSet::new(),
+            compression: vec![],
         }
     }
 }
@@ -71,12 +74,25 @@ impl Offer<Negotiate> {
         }
         self
     }
+    pub fn compression(mut self, compression: &[Compression]) -> Self {
+        self.state.compression = compression.to_vec();
+        self
+    }
+    pub fn ack(&self) -> &MyPkgAck {
+        &self.state.ack
+    }
     pub fn peers(&self) -> Vec<String> {
         self.state.peers.iter().map(|v| v.to_owned()).collect()
     }
     pub async fn negotiate(mut self) -> Result<Exchange<Ready>, Error> {
         let msg = NegotiateMyPkg {
             md5sum: self.state.mypkg.md5sum.to_owned(),
+            compression: self
+                .state
+                .compression
+                .iter()
+                .map(|c| c.to_string())
+                .collect(),
         };
         self.borrow_mut()
             .inner
@@ -94,7 +110,11 @@ impl Offer<Negotiate> {
 
         Ok(Exchange {
             inner: self.inner,
-            state: Ready,
+            state: Ready {
+                mypkg: self.state.mypkg,
+                ack: self.state.ack,
+                compression: resp.compression,
+            },
         })
     }
 }
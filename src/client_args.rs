@@ -1,16 +1,51 @@
 use clap::{Parser, Subcommand};
 
+use crate::protocol::{Compression, Digest};
+use crate::tls::ClientTls;
+use anyhow::{bail, Result};
+
 /// A simple CLI tool with subcommands
 #[derive(Parser, Debug)]
 #[command(name = "cli-tool")]
 #[command(about = "An example CLI tool using clap with subcommands")]
 pub struct Cli {
+    /// A tcp address, or unix:<path> to connect to a unix socket
     #[arg(short, long, default_value = "127.0.0.1:8080")]
     pub connect_to: String,
+    /// Talk to a server over the stdin and stdout of this command instead of connecting,
+    /// e.g. "ssh buildhost blobfish-server --stdio"
+    #[arg(long, value_name = "COMMAND")]
+    pub exec: Option<String>,
+    /// Connect over TLS, trusting servers signed by this CA certificate
+    #[arg(long, value_name = "PEM")]
+    pub ca: Option<String>,
+    /// The name the server certificate has to be issued for
+    #[arg(long, default_value = "localhost")]
+    pub server_name: String,
+    /// The client certificate to present to servers that ask for one
+    #[arg(long, value_name = "PEM", requires = "key")]
+    pub cert: Option<String>,
+    /// The key of the client certificate
+    #[arg(long, value_name = "PEM", requires = "cert")]
+    pub key: Option<String>,
     #[command(subcommand)]
     pub command: Commands,
 }
 
+impl Cli {
+    // the tls settings to connect with, None connects over plain tcp
+    pub fn tls(&self) -> Result<Option<ClientTls>> {
+        let Some(ca) = &self.ca else {
+            if self.cert.is_some() {
+                bail!("--cert needs --ca, a client certificate is only sent over tls");
+            }
+            return Ok(None);
+        };
+        let identity = self.cert.as_deref().zip(self.key.as_deref());
+        Ok(Some(ClientTls::load(ca, &self.server_name, identity)?))
+    }
+}
+
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     /// Upload a file
@@ -21,13 +56,44 @@ pub enum Commands {
         /// The file to upload
         #[arg(value_name = "FILE", required = true, num_args = 1)]
         file: Vec<String>,
+        /// Tag the package, may be repeated
+        #[arg(short, long)]
+        tag: Vec<String>,
+        /// The digest the package is summed with: md5, sha256 or blake3
+        #[arg(short, long, default_value = "sha256")]
+        digest: Digest,
+        /// Compress pieces on the wire with zstd or lz4 if the server supports it
+        #[arg(long)]
+        compress: Option<Compression>,
     },
-    /// Download a file
+    /// Download a package
     Download {
-        /// The file to download
-        #[arg(value_name = "FILE")]
-        file: Vec<String>,
+        /// The sum or name of the package to download
+        #[arg(value_name = "PACKAGE", required = true)]
+        package: String,
+        /// The directory to write the package files into
+        #[arg(short, long, default_value = ".")]
+        output: String,
+        /// Ask the server to compress pieces on the wire with zstd or lz4
+        #[arg(long)]
+        compress: Option<Compression>,
+    },
+    /// List packages held by the server
+    List {
+        /// Only list packages with this name
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Only list packages carrying this tag, may be repeated
+        #[arg(short, long)]
+        tag: Vec<String>,
+        /// Skip this many packages
+        #[arg(long, default_value_t = 0)]
+        offset: u64,
+        /// List at most this many packages
+        #[arg(long)]
+        limit: Option<u64>,
+        /// Print the manifests as JSON instead of a table
+        #[arg(long)]
+        json: bool,
     },
-    /// List files
-    List,
 }
@@ -1,7 +1,13 @@
+// each typestate lives in a module named after its directory, e.g. client::offer::offer
+#![allow(clippy::module_inception)]
+
 pub mod client;
 pub mod client_args;
 pub mod protocol;
 pub mod server;
+pub mod server_args;
+pub mod tls;
+pub mod transport;
 pub mod upload;
 
 // re-export from sub-crates
@@ -14,100 +20,902 @@ mod tests {
 
     use anyhow::Error;
     use protocol::hash_file;
-    use serde_bytes::ByteBuf;
     use std::collections::HashSet;
-    use tokio::net::TcpListener;
+    use tokio::{net::TcpListener, task::JoinHandle};
+    use tokio_util::bytes::Bytes;
     use tokio_util::sync::CancellationToken;
 
     use crate::{
-        client::Offer,
-        client_args::Commands,
-        protocol::{MyPkg, Piece, BLOCK_SIZE},
+        client::{Fetch, Offer},
+        protocol::{
+            Compression, Digest, Done, ErrorMsg, FetchMyPkg, Hello, ListMyPkg, ListMyPkgAck, MyPkg,
+            PeerError, Piece, BLOCK_SIZE, FEATURE_RESUME, PROTOCOL_VERSION,
+        },
+        server::server::{bitfield_path, file_path, quarantine_path, INDEX_DIR},
+        tls::{ClientTls, ServerTls},
+        transport::{self, Listener},
         Client, Server,
     };
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+
+    async fn upload(name: String, file: Vec<String>, server_addr: String) -> Result<MyPkg, Error> {
+        upload_mypkg(MyPkg::new(name, file, Digest::Sha256)?, server_addr).await
+    }
+
+    async fn upload_mypkg(mypkg: MyPkg, server_addr: String) -> Result<MyPkg, Error> {
+        println!("connect to {}", &server_addr);
+        let mut state = Offer::new(Client::open(server_addr).await?)
+            .offer(mypkg.clone())
+            .await?
+            .add_peers(vec!["127.0.0.1:2040".into()])
+            .negotiate()
+            .await?;
+        for file in state.wanted() {
+            state.send_file(file).await?;
+        }
+        state.done().await?;
+        Ok(mypkg)
+    }
+
+    async fn serve(
+        listener: impl Listener + 'static,
+        ctx: CancellationToken,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Server::new(listener, HashSet::new())
+            .await?
+            .serve(ctx)
+            .await?;
+        Ok(())
+    }
+
+    // write a file of len deterministic bytes, salted so every test gets its own md5sum
+    fn fixture(name: &str, len: usize) -> Result<String, Error> {
+        let dir = std::env::temp_dir().join("blobfish-fixtures");
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(name).to_string_lossy().to_string();
+        let data: Vec<u8> = name
+            .bytes()
+            .cycle()
+            .zip(0..len)
+            .map(|(b, i)| b ^ (i % 251) as u8)
+            .collect();
+        std::fs::write(&path, data)?;
+        Ok(path)
+    }
 
     #[tokio::test]
     async fn test_end_to_end() -> Result<(), Error> {
-        async fn client(name: String, file: Vec<String>, server_addr: String) -> Result<(), Error> {
-            println!("connect to {}", &server_addr);
-            let mypkg = MyPkg::new(name, file)?;
-            let mut state = Offer::new(Client::open(server_addr).await?)
+        // the session runs over an in memory pipe, no port needed
+        let (server_listener, connector) = transport::pipe();
+        let ctx = CancellationToken::new();
+        let server_ctx = ctx.clone();
+
+        let server_handle =
+            tokio::spawn(async move { serve(server_listener, server_ctx).await.unwrap() });
+
+        let crushingit = "src/fixtures/crushingit.gif";
+        let wombatchew = "src/fixtures/wombatchew.gif";
+        let mypkg = MyPkg::new(
+            "test_end_to_end".into(),
+            vec![crushingit.into(), wombatchew.into()],
+            Digest::Sha256,
+        )?;
+        let client_handle = tokio::spawn(async move {
+            let client = Client::handshake(connector.connect()?, Hello::new()).await?;
+            let mut state = Offer::new(client).offer(mypkg).await?.negotiate().await?;
+            for file in state.wanted() {
+                state.send_file(file).await?;
+            }
+            state.done().await
+        });
+        client_handle.await??;
+        ctx.cancel();
+        server_handle.await?;
+
+        let original_crushingit = hash_file(crushingit, Digest::Sha256)?;
+        let original_wombatchew = hash_file(wombatchew, Digest::Sha256)?;
+        let blobfish_crushingit = hash_file(
+            &format!(
+                "data/{}/{}",
+                &original_crushingit.md5sum,
+                original_crushingit.filename()
+            ),
+            Digest::Sha256,
+        )?;
+        let blobfish_wombatchew = hash_file(
+            &format!(
+                "data/{}/{}",
+                &original_wombatchew.md5sum,
+                original_wombatchew.filename()
+            ),
+            Digest::Sha256,
+        )?;
+
+        assert_eq!(original_crushingit.md5sum, blobfish_crushingit.md5sum);
+        assert_eq!(original_wombatchew.md5sum, blobfish_wombatchew.md5sum);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download() -> Result<(), Error> {
+        let server_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let server_addr = server_listener.local_addr()?.to_string();
+        let ctx = CancellationToken::new();
+        let server_ctx = ctx.clone();
+        let server_handle =
+            tokio::spawn(async move { serve(server_listener, server_ctx).await.unwrap() });
+
+        let original = fixture("test_download.bin", 2 * BLOCK_SIZE)?;
+        let mypkg = upload(
+            "test_download".into(),
+            vec![original.clone()],
+            server_addr.clone(),
+        )
+        .await?;
+
+        let out = std::env::temp_dir().join("blobfish-test-download");
+        let out = out.to_string_lossy().to_string();
+        for query in [mypkg.md5sum.clone(), mypkg.name.clone()] {
+            let mut state = Fetch::new(Client::open(server_addr.clone()).await?)
+                .fetch(FetchMyPkg::new(query))
+                .await?;
+            assert_eq!(state.mypkg().md5sum, mypkg.md5sum);
+            let files = state.receive(&out).await?;
+            assert_eq!(files.len(), 1);
+            assert_eq!(
+                files[0].md5sum,
+                hash_file(&original, Digest::Sha256)?.md5sum
+            );
+        }
+
+        let missing = Fetch::new(Client::open(server_addr).await?)
+            .fetch(FetchMyPkg::new("no-such-package".into()))
+            .await;
+        assert!(missing.is_err());
+
+        ctx.cancel();
+        server_handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list() -> Result<(), Error> {
+        let server_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let server_addr = server_listener.local_addr()?.to_string();
+        let ctx = CancellationToken::new();
+        let server_ctx = ctx.clone();
+        let server_handle =
+            tokio::spawn(async move { serve(server_listener, server_ctx).await.unwrap() });
+
+        let mut older = MyPkg::new(
+            "test_list".into(),
+            vec![fixture("test_list_older.bin", 2 * BLOCK_SIZE)?],
+            Digest::Sha256,
+        )?;
+        older.tags = vec!["stable".into()];
+        let older = upload_mypkg(older, server_addr.clone()).await?;
+        let mut newer = MyPkg::new(
+            "test_list".into(),
+            vec![fixture("test_list_newer.bin", 2 * BLOCK_SIZE)?],
+            Digest::Sha256,
+        )?;
+        newer.built_on = older.built_on + 1;
+        newer.tags = vec!["stable".into(), "nightly".into()];
+        let newer = upload_mypkg(newer, server_addr.clone()).await?;
+
+        let list = |query: ListMyPkg| {
+            let server_addr = server_addr.clone();
+            async move { Client::open(server_addr).await?.list(query).await }
+        };
+        let all = list(ListMyPkg {
+            name: Some("test_list".into()),
+            ..Default::default()
+        })
+        .await?;
+        assert_eq!(all.total, 2);
+        let listed: Vec<String> = all.mypkgs.iter().map(|m| m.md5sum.clone()).collect();
+        assert_eq!(listed, vec![newer.md5sum.clone(), older.md5sum.clone()]);
+        assert_eq!(all.mypkgs[1].files.len(), 1);
+
+        let page = list(ListMyPkg {
+            name: Some("test_list".into()),
+            offset: 1,
+            limit: Some(1),
+            ..Default::default()
+        })
+        .await?;
+        assert_eq!(page.total, 2);
+        assert_eq!(page.mypkgs.len(), 1);
+        assert_eq!(page.mypkgs[0].md5sum, older.md5sum);
+
+        let nightly = list(ListMyPkg {
+            name: Some("test_list".into()),
+            tags: vec!["nightly".into()],
+            ..Default::default()
+        })
+        .await?;
+        assert_eq!(nightly.total, 1);
+        assert_eq!(nightly.mypkgs[0].md5sum, newer.md5sum);
+
+        ctx.cancel();
+        server_handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_index_survives_restart() -> Result<(), Error> {
+        async fn start() -> Result<(String, CancellationToken, JoinHandle<()>), Error> {
+            let server_listener = TcpListener::bind("127.0.0.1:0").await?;
+            let server_addr = server_listener.local_addr()?.to_string();
+            let ctx = CancellationToken::new();
+            let server_ctx = ctx.clone();
+            let server_handle =
+                tokio::spawn(async move { serve(server_listener, server_ctx).await.unwrap() });
+            Ok((server_addr, ctx, server_handle))
+        }
+        async fn fetch(server_addr: String, md5sum: String) -> Result<MyPkg, Error> {
+            let state = Fetch::new(Client::open(server_addr).await?)
+                .fetch(FetchMyPkg::new(md5sum))
+                .await?;
+            Ok(state.mypkg().clone())
+        }
+
+        let (server_addr, ctx, server_handle) = start().await?;
+        let original = fixture("test_index_survives_restart.bin", 2 * BLOCK_SIZE)?;
+        let mypkg = upload(
+            "test_index_survives_restart".into(),
+            vec![original],
+            server_addr,
+        )
+        .await?;
+        ctx.cancel();
+        server_handle.await?;
+
+        // a fresh server rebuilds its index from disk
+        let (server_addr, ctx, server_handle) = start().await?;
+        let restored = fetch(server_addr, mypkg.md5sum.clone()).await?;
+        assert_eq!(restored.name, mypkg.name);
+        assert_eq!(restored.files.len(), 1);
+        ctx.cancel();
+        server_handle.await?;
+
+        // a package whose files went missing is no longer served
+        std::fs::remove_file(file_path(&mypkg.files[0]))?;
+        let (server_addr, ctx, server_handle) = start().await?;
+        assert!(fetch(server_addr, mypkg.md5sum.clone()).await.is_err());
+        ctx.cancel();
+        server_handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rejects_corrupt_file() -> Result<(), Error> {
+        let server_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let server_addr = server_listener.local_addr()?.to_string();
+        let ctx = CancellationToken::new();
+        let server_ctx = ctx.clone();
+        let server_handle =
+            tokio::spawn(async move { serve(server_listener, server_ctx).await.unwrap() });
+
+        // the manifest describes the fixture, but the bytes sent are different. without piece
+        // sums the corruption is only caught once the whole file is hashed
+        let path = fixture("test_rejects_corrupt_file.bin", 2 * BLOCK_SIZE)?;
+        let mut mypkg = MyPkg::new(
+            "test_rejects_corrupt_file".into(),
+            vec![path.clone()],
+            Digest::Sha256,
+        )?;
+        mypkg.files[0].pieces.clear();
+        std::fs::write(&path, vec![0u8; 2 * BLOCK_SIZE])?;
+        assert!(upload_mypkg(mypkg.clone(), server_addr.clone())
+            .await
+            .is_err());
+
+        assert!(std::path::Path::new(&quarantine_path(&mypkg.files[0])).exists());
+        assert!(!std::path::Path::new(&file_path(&mypkg.files[0])).exists());
+        let fetched = Fetch::new(Client::open(server_addr).await?)
+            .fetch(FetchMyPkg::new(mypkg.md5sum))
+            .await;
+        assert!(fetched.is_err());
+
+        ctx.cancel();
+        server_handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resume_upload() -> Result<(), Error> {
+        async fn start() -> Result<(String, CancellationToken, JoinHandle<()>), Error> {
+            let server_listener = TcpListener::bind("127.0.0.1:0").await?;
+            let server_addr = server_listener.local_addr()?.to_string();
+            let ctx = CancellationToken::new();
+            let server_ctx = ctx.clone();
+            let server_handle =
+                tokio::spawn(async move { serve(server_listener, server_ctx).await.unwrap() });
+            Ok((server_addr, ctx, server_handle))
+        }
+        async fn negotiate(
+            mypkg: &MyPkg,
+            server_addr: String,
+        ) -> Result<client::exchange::Exchange<client::exchange::Ready>, Error> {
+            Offer::new(Client::open(server_addr).await?)
                 .offer(mypkg.clone())
                 .await?
-                .add_peers(vec!["127.0.0.1:2040".into()])
                 .negotiate()
-                .await?;
-            // tricky, how do i do multiple files? i need a loop or something
-            let mut buf: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
-            for file in mypkg.files {
-                dbg!(&file);
-                let piece_count = file.clone().chunk_count();
-                println!("send file {} piece_count {}", file.path, piece_count);
-                state
-                    .exchange([0, piece_count as u64], file.clone())
-                    .await?;
-                let read_at = file.read_at()?;
-                for piece in 0..piece_count {
-                    read_at(piece as u64, &mut buf)?;
-                    let p = Piece {
-                        piece: piece as u64,
-                        ack: None,
-                        data: ByteBuf::from(buf),
-                    };
-                    state.send(p).await?
-                }
+                .await
+        }
+
+        let path = fixture("test_resume_upload.bin", 8 * BLOCK_SIZE)?;
+        let mypkg = MyPkg::new("test_resume_upload".into(), vec![path], Digest::Sha256)?;
+        let file = mypkg.files[0].clone();
+        let _ = std::fs::remove_file(file_path(&file));
+
+        // the first connection goes away after half the pieces
+        let (server_addr, ctx, server_handle) = start().await?;
+        let mut state = negotiate(&mypkg, server_addr).await?;
+        assert_eq!(state.exchange([0, 8], file.clone()).await?, vec![[0, 8]]);
+        let read_at = file.clone().read_at()?;
+        let mut buf = [0; BLOCK_SIZE];
+        for piece in 0..4 {
+            read_at(piece, &mut buf)?;
+            let p = Piece {
+                piece,
+                ack: None,
+                compression: None,
+                data: Bytes::copy_from_slice(&buf),
+            };
+            state.send(p).await?;
+        }
+        drop(state);
+        ctx.cancel();
+        server_handle.await?;
+
+        // a restarted server only asks for what it is missing
+        let (server_addr, ctx, server_handle) = start().await?;
+        let mut state = negotiate(&mypkg, server_addr.clone()).await?;
+        assert_eq!(state.exchange([0, 8], file.clone()).await?, vec![[4, 8]]);
+        for piece in 4..8 {
+            read_at(piece, &mut buf)?;
+            let p = Piece {
+                piece,
+                ack: None,
+                compression: None,
+                data: Bytes::copy_from_slice(&buf),
+            };
+            state.send(p).await?;
+        }
+        assert!(state.missing().await?.is_empty());
+        state.done().await?;
+        assert!(!std::path::Path::new(&bitfield_path(&file)).exists());
+
+        let fetched = Fetch::new(Client::open(server_addr).await?)
+            .fetch(FetchMyPkg::new(mypkg.md5sum.clone()))
+            .await?;
+        assert_eq!(fetched.mypkg().md5sum, mypkg.md5sum);
+        drop(fetched);
+        ctx.cancel();
+        server_handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_skips_stored_files() -> Result<(), Error> {
+        // the next version renames the shared file and adds a new one
+        let shared = fixture("test_skips_stored_files.bin", 2 * BLOCK_SIZE)?;
+        let renamed = shared.replace(".bin", "_renamed.bin");
+        std::fs::copy(&shared, &renamed)?;
+        let added = fixture("test_skips_stored_files_added.bin", 2 * BLOCK_SIZE)?;
+        let mypkg = MyPkg::new(
+            "test_skips_stored_files_v2".into(),
+            vec![renamed, added],
+            Digest::Sha256,
+        )?;
+        // forget the second version from any earlier run
+        let _ = std::fs::remove_file(format!("{}/{}", INDEX_DIR, mypkg.md5sum));
+        for file in &mypkg.files {
+            let _ = std::fs::remove_file(file_path(file));
+        }
+
+        let server_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let server_addr = server_listener.local_addr()?.to_string();
+        let ctx = CancellationToken::new();
+        let server_ctx = ctx.clone();
+        let server_handle =
+            tokio::spawn(async move { serve(server_listener, server_ctx).await.unwrap() });
+
+        upload(
+            "test_skips_stored_files".into(),
+            vec![shared],
+            server_addr.clone(),
+        )
+        .await?;
+
+        let mut state = Offer::new(Client::open(server_addr.clone()).await?)
+            .offer(mypkg.clone())
+            .await?
+            .negotiate()
+            .await?;
+        let wanted = state.wanted();
+        assert_eq!(wanted.len(), 1);
+        assert_eq!(wanted[0].md5sum, mypkg.files[1].md5sum);
+        for file in wanted {
+            state.send_file(file).await?;
+        }
+        state.done().await?;
+
+        let out = std::env::temp_dir().join("blobfish-test-skips-stored-files");
+        let mut fetched = Fetch::new(Client::open(server_addr).await?)
+            .fetch(FetchMyPkg::new(mypkg.md5sum.clone()))
+            .await?;
+        let files = fetched.receive(&out.to_string_lossy()).await?;
+        assert_eq!(files.len(), 2);
+        drop(fetched);
+
+        ctx.cancel();
+        server_handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_partial_pieces() -> Result<(), Error> {
+        let server_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let server_addr = server_listener.local_addr()?.to_string();
+        let ctx = CancellationToken::new();
+        let server_ctx = ctx.clone();
+        let server_handle =
+            tokio::spawn(async move { serve(server_listener, server_ctx).await.unwrap() });
+
+        let lengths = [0, 1, BLOCK_SIZE, BLOCK_SIZE + 1, 3 * 1024 * 1024 + 7];
+        let mut originals = vec![];
+        for length in lengths {
+            originals.push(fixture(
+                &format!("test_partial_pieces_{}.bin", length),
+                length,
+            )?);
+        }
+        let mypkg = upload(
+            "test_partial_pieces".into(),
+            originals.clone(),
+            server_addr.clone(),
+        )
+        .await?;
+        for file in &mypkg.files {
+            let stored = hash_file(&file_path(file), Digest::Sha256)?;
+            assert_eq!(stored.length, file.length);
+            assert_eq!(stored.md5sum, file.md5sum);
+        }
+
+        let out = std::env::temp_dir().join("blobfish-test-partial-pieces");
+        let mut fetched = Fetch::new(Client::open(server_addr).await?)
+            .fetch(FetchMyPkg::new(mypkg.md5sum.clone()))
+            .await?;
+        let files = fetched.receive(&out.to_string_lossy()).await?;
+        for (downloaded, length) in files.iter().zip(lengths) {
+            assert_eq!(downloaded.length, length as u64);
+        }
+        drop(fetched);
+
+        ctx.cancel();
+        server_handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retransmits_corrupt_piece() -> Result<(), Error> {
+        let server_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let server_addr = server_listener.local_addr()?.to_string();
+        let ctx = CancellationToken::new();
+        let server_ctx = ctx.clone();
+        let server_handle =
+            tokio::spawn(async move { serve(server_listener, server_ctx).await.unwrap() });
+
+        let path = fixture("test_retransmits_corrupt_piece.bin", 3 * BLOCK_SIZE + 5)?;
+        let mypkg = MyPkg::new(
+            "test_retransmits_corrupt_piece".into(),
+            vec![path],
+            Digest::Sha256,
+        )?;
+        let file = mypkg.files[0].clone();
+        let _ = std::fs::remove_file(format!("{}/{}", INDEX_DIR, mypkg.md5sum));
+        let _ = std::fs::remove_file(file_path(&file));
+        let _ = std::fs::remove_file(bitfield_path(&file));
+
+        let mut state = Offer::new(Client::open(server_addr.clone()).await?)
+            .offer(mypkg.clone())
+            .await?
+            .negotiate()
+            .await?;
+        assert_eq!(state.exchange([0, 4], file.clone()).await?, vec![[0, 4]]);
+        let read_at = file.clone().read_at()?;
+        let mut buf = [0; BLOCK_SIZE];
+        for piece in 0..4 {
+            let n = read_at(piece, &mut buf)?;
+            if piece == 2 {
+                buf[0] ^= 0xff;
             }
-            Ok(())
+            let p = Piece {
+                piece,
+                ack: None,
+                compression: None,
+                data: Bytes::copy_from_slice(&buf[..n]),
+            };
+            state.send(p).await?;
         }
+        // only the corrupt piece is asked for again
+        assert_eq!(state.missing().await?, vec![[2, 3]]);
+        let n = read_at(2, &mut buf)?;
+        let p = Piece {
+            piece: 2,
+            ack: None,
+            compression: None,
+            data: Bytes::copy_from_slice(&buf[..n]),
+        };
+        state.send(p).await?;
+        assert!(state.missing().await?.is_empty());
+        state.done().await?;
+        assert_eq!(
+            hash_file(&file_path(&file), Digest::Sha256)?.md5sum,
+            file.md5sum
+        );
 
-        async fn server(
-            server_addr: TcpListener,
-            ctx: CancellationToken,
-        ) -> Result<(), Box<dyn std::error::Error>> {
-            println!(
-                "listen on {}",
-                &server_addr.local_addr().unwrap().to_string()
-            );
-            Server::new(server_addr, HashSet::new())
+        ctx.cancel();
+        server_handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_digests() -> Result<(), Error> {
+        let server_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let server_addr = server_listener.local_addr()?.to_string();
+        let ctx = CancellationToken::new();
+        let server_ctx = ctx.clone();
+        let server_handle =
+            tokio::spawn(async move { serve(server_listener, server_ctx).await.unwrap() });
+
+        let path = fixture("test_digests.bin", BLOCK_SIZE + 3)?;
+        let out = std::env::temp_dir().join("blobfish-test-digests");
+        for digest in Digest::ALL {
+            let mypkg = MyPkg::new(
+                format!("test_digests_{}", digest),
+                vec![path.clone()],
+                digest,
+            )?;
+            assert_eq!(mypkg.md5sum.len(), digest.hex_len());
+            upload_mypkg(mypkg.clone(), server_addr.clone()).await?;
+            let mut fetched = Fetch::new(Client::open(server_addr.clone()).await?)
+                .fetch(FetchMyPkg::new(mypkg.md5sum.clone()))
+                .await?;
+            assert_eq!(fetched.mypkg().digest, digest);
+            let files = fetched.receive(&out.to_string_lossy()).await?;
+            assert_eq!(files[0].md5sum, mypkg.files[0].md5sum);
+            drop(fetched);
+        }
+
+        // a file summed with another digest than its package is refused
+        let mut mixed = MyPkg::new("test_digests_mixed".into(), vec![path], Digest::Sha256)?;
+        mixed.files[0] = hash_file(&mixed.files[0].path, Digest::Blake3)?;
+        assert!(upload_mypkg(mixed, server_addr).await.is_err());
+
+        ctx.cancel();
+        server_handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hello() -> Result<(), Error> {
+        let server_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let server_addr = server_listener.local_addr()?.to_string();
+        let ctx = CancellationToken::new();
+        let server_ctx = ctx.clone();
+        let server_handle =
+            tokio::spawn(async move { serve(server_listener, server_ctx).await.unwrap() });
+
+        let client = Client::open(server_addr.clone()).await?;
+        assert_eq!(client.version(), PROTOCOL_VERSION);
+        assert!(client.features().contains(&FEATURE_RESUME.to_string()));
+        drop(client);
+
+        // only the features both sides offer are used
+        let hello = Hello {
+            features: vec!["digest:blake3".into(), "teleport".into()],
+            ..Hello::new()
+        };
+        let client = Client::connect(server_addr.clone(), hello).await?;
+        assert_eq!(client.features(), ["digest:blake3".to_string()]);
+        drop(client);
+
+        // a client from the future that dropped support for our version is refused
+        let hello = Hello {
+            version: PROTOCOL_VERSION + 2,
+            min_version: PROTOCOL_VERSION + 1,
+            ..Hello::new()
+        };
+        let refused = Client::connect(server_addr, hello).await;
+        assert!(refused
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("incompatible protocol version"));
+
+        ctx.cancel();
+        server_handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_peer_error() -> Result<(), Error> {
+        let server_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let server_addr = server_listener.local_addr()?.to_string();
+        let ctx = CancellationToken::new();
+        let server_ctx = ctx.clone();
+        let server_handle =
+            tokio::spawn(async move { serve(server_listener, server_ctx).await.unwrap() });
+
+        let path = fixture("test_peer_error.bin", 2 * BLOCK_SIZE)?;
+        let mypkg = MyPkg::new("test_peer_error".into(), vec![path], Digest::Sha256)?;
+        let file = mypkg.files[0].clone();
+        let _ = std::fs::remove_file(bitfield_path(&file));
+        let _ = std::fs::remove_file(file_path(&file));
+
+        let mut state = Offer::new(Client::open(server_addr).await?)
+            .offer(mypkg)
+            .await?
+            .negotiate()
+            .await?;
+        state.exchange([0, 2], file).await?;
+        let p = Piece {
+            piece: 7,
+            ack: None,
+            compression: None,
+            data: Bytes::from(vec![0; BLOCK_SIZE]),
+        };
+        state.send(p).await?;
+        // the server says why it gave up instead of just hanging up
+        let e = state.missing().await.err().unwrap();
+        let PeerError(msg) = e.downcast_ref::<PeerError>().unwrap();
+        assert_eq!(msg.code, ErrorMsg::UNEXPECTED);
+        assert!(msg.message.contains("out of bounds"));
+        drop(state);
+
+        ctx.cancel();
+        server_handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compression() -> Result<(), Error> {
+        let server_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let server_addr = server_listener.local_addr()?.to_string();
+        let ctx = CancellationToken::new();
+        let server_ctx = ctx.clone();
+        let server_handle =
+            tokio::spawn(async move { serve(server_listener, server_ctx).await.unwrap() });
+
+        let out = std::env::temp_dir().join("blobfish-test-compression");
+        for c in Compression::ALL {
+            // a file that compresses well next to one that does not, each ending in a short piece
+            let text = fixture(&format!("test_compression_{}.txt", c), 3 * BLOCK_SIZE + 11)?;
+            let noise = std::env::temp_dir()
+                .join("blobfish-fixtures")
+                .join(format!("test_compression_{}.noise", c));
+            let mut seed = c as u64 + 11;
+            let data: Vec<u8> = (0..2 * BLOCK_SIZE + 5)
+                .map(|_| {
+                    seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                    (seed >> 56) as u8
+                })
+                .collect();
+            std::fs::write(&noise, data)?;
+            let paths = vec![text, noise.to_string_lossy().to_string()];
+            let mypkg = MyPkg::new(format!("test_compression_{}", c), paths, Digest::Blake3)?;
+
+            let mut state = Offer::new(Client::open(server_addr.clone()).await?)
+                .offer(mypkg.clone())
                 .await?
-                .serve(ctx)
+                .compression(&[c])
+                .negotiate()
+                .await?;
+            assert_eq!(state.state.compression, Some(c));
+            for file in state.wanted() {
+                state.send_file(file).await?;
+            }
+            state.done().await?;
+            // the server keeps the files as they are
+            for file in &mypkg.files {
+                assert_eq!(
+                    hash_file(&file_path(file), file.digest)?.md5sum,
+                    file.md5sum
+                );
+            }
+
+            let mut fetched = Fetch::new(Client::open(server_addr.clone()).await?)
+                .fetch(FetchMyPkg::new(mypkg.md5sum.clone()).with_compression(&[c]))
                 .await?;
-            Ok(())
+            let files = fetched.receive(&out.to_string_lossy()).await?;
+            assert_eq!(files.len(), mypkg.files.len());
+            for (got, want) in files.iter().zip(&mypkg.files) {
+                assert_eq!(got.md5sum, want.md5sum);
+            }
+            drop(fetched);
+        }
+
+        ctx.cancel();
+        server_handle.await?;
+        Ok(())
+    }
+
+    // a throwaway certificate authority for tls tests
+    struct Ca {
+        key: KeyPair,
+        cert: rcgen::Certificate,
+    }
+
+    impl Ca {
+        fn new() -> Result<Ca, Error> {
+            let key = KeyPair::generate()?;
+            let mut params = CertificateParams::new(Vec::<String>::new())?;
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let cert = params.self_signed(&key)?;
+            Ok(Ca { key, cert })
+        }
+        // a certificate and key for name signed by the ca, as pem
+        fn issue(&self, name: &str) -> Result<(String, String), Error> {
+            let key = KeyPair::generate()?;
+            let cert = CertificateParams::new(vec![name.to_string()])?
+                .signed_by(&key, &self.cert, &self.key)?;
+            Ok((cert.pem(), key.serialize_pem()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tls() -> Result<(), Error> {
+        let authority = Ca::new()?;
+        let ca = authority.cert.pem();
+        let (server_cert, server_key) = authority.issue("localhost")?;
+        let (client_cert, client_key) = authority.issue("client")?;
+        let other_ca = Ca::new()?.cert.pem();
+
+        let plain = ServerTls::new(server_cert.as_bytes(), server_key.as_bytes(), None)?;
+        let mutual = ServerTls::new(
+            server_cert.as_bytes(),
+            server_key.as_bytes(),
+            Some(ca.as_bytes()),
+        )?;
+        let ctx = CancellationToken::new();
+        let mut addrs = vec![];
+        let mut handles = vec![];
+        for tls in [plain, mutual] {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            addrs.push(listener.local_addr()?.to_string());
+            let server = Server::new(listener, HashSet::new()).await?.tls(tls);
+            let server_ctx = ctx.clone();
+            handles.push(tokio::spawn(async move {
+                server.serve(server_ctx).await.unwrap()
+            }));
         }
+        let (tls_addr, mutual_addr) = (addrs[0].clone(), addrs[1].clone());
+
+        let anonymous = ClientTls::new(ca.as_bytes(), "localhost", None)?;
+        let identified = ClientTls::new(
+            ca.as_bytes(),
+            "localhost",
+            Some((client_cert.as_bytes(), client_key.as_bytes())),
+        )?;
+
+        // a package goes up and comes back down over tls
+        let path = fixture("test_tls.bin", BLOCK_SIZE + 9)?;
+        let mypkg = MyPkg::new("test_tls".into(), vec![path], Digest::Sha256)?;
+        let mut state = Offer::new(Client::open_tls(tls_addr.clone(), &anonymous).await?)
+            .offer(mypkg.clone())
+            .await?
+            .negotiate()
+            .await?;
+        for file in state.wanted() {
+            state.send_file(file).await?;
+        }
+        state.done().await?;
+        let out = std::env::temp_dir().join("blobfish-test-tls");
+        let mut fetched = Fetch::new(Client::open_tls(tls_addr.clone(), &anonymous).await?)
+            .fetch(FetchMyPkg::new(mypkg.md5sum.clone()))
+            .await?;
+        let files = fetched.receive(&out.to_string_lossy()).await?;
+        assert_eq!(files[0].md5sum, mypkg.files[0].md5sum);
+        drop(fetched);
+
+        // plain tcp, or a server we have no reason to trust, never gets to hello
+        assert!(Client::open(tls_addr.clone()).await.is_err());
+        let untrusted = ClientTls::new(other_ca.as_bytes(), "localhost", None)?;
+        assert!(Client::open_tls(tls_addr.clone(), &untrusted)
+            .await
+            .is_err());
+        let misnamed = ClientTls::new(ca.as_bytes(), "blobfish.example", None)?;
+        assert!(Client::open_tls(tls_addr, &misnamed).await.is_err());
 
-        let server_listener = TcpListener::bind("127.0.0.1:8080").await?;
-        let server_addr = server_listener.local_addr().unwrap().clone();
+        // with mutual tls only clients with a certificate from the ca get in
+        assert!(Client::open_tls(mutual_addr.clone(), &anonymous)
+            .await
+            .is_err());
+        let mut client = Client::open_tls(mutual_addr, &identified).await?;
+        assert_eq!(client.version(), PROTOCOL_VERSION);
+        client.list(ListMyPkg::default()).await?;
+        drop(client);
+
+        ctx.cancel();
+        for handle in handles {
+            handle.await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket() -> Result<(), Error> {
+        let dir = std::env::temp_dir().join("blobfish-test-unix");
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("server.sock");
+        let _ = std::fs::remove_file(&path);
+        let addr = format!("unix:{}", path.to_string_lossy());
         let ctx = CancellationToken::new();
         let server_ctx = ctx.clone();
+        let listener = transport::bind(&addr).await?;
+        let server_handle = tokio::spawn(async move { serve(listener, server_ctx).await.unwrap() });
 
+        let path = fixture("test_unix_socket.bin", 2 * BLOCK_SIZE + 1)?;
+        let mypkg = upload("test_unix_socket".into(), vec![path], addr.clone()).await?;
+        let out = std::env::temp_dir().join("blobfish-test-unix-out");
+        let mut fetched = Fetch::new(Client::open(addr).await?)
+            .fetch(FetchMyPkg::new(mypkg.md5sum.clone()))
+            .await?;
+        let files = fetched.receive(&out.to_string_lossy()).await?;
+        assert_eq!(files[0].md5sum, mypkg.files[0].md5sum);
+        drop(fetched);
+
+        ctx.cancel();
+        server_handle.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_serve_stream() -> Result<(), Error> {
+        // the same session a server started with --stdio runs, over one end of a pipe
+        let (client, server) = tokio::io::duplex(4 * BLOCK_SIZE);
         let server_handle =
-            tokio::spawn(async move { server(server_listener, server_ctx).await.unwrap() });
+            tokio::spawn(
+                async move { Server::serve_stream(Box::new(server), HashSet::new()).await },
+            );
 
-        let name = "test_end_to_end".into();
-        let crushingit = "src/fixtures/crushingit.gif";
-        let wombatchew = "src/fixtures/wombatchew.gif";
-        let file = vec![crushingit.into(), wombatchew.into()];
-        let client_handle =
-            tokio::spawn(async move { client(name, file, server_addr.to_string()).await.unwrap() });
-        client_handle.await?;
-        ctx.cancel();
-        server_handle.await?;
-
-        let original_crushingit = hash_file(crushingit)?;
-        let original_wombatchew = hash_file(wombatchew)?;
-        let blobfish_crushingit = hash_file(&format!(
-            "data/{}/{}",
-            &original_crushingit.md5sum,
-            original_crushingit.filename()
-        ))?;
-        let blobfish_wombatchew = hash_file(&format!(
-            "data/{}/{}",
-            &original_wombatchew.md5sum,
-            original_wombatchew.filename()
-        ))?;
+        let path = fixture("test_serve_stream.bin", BLOCK_SIZE + 1)?;
+        let mypkg = MyPkg::new("test_serve_stream".into(), vec![path], Digest::Sha256)?;
+        let mut state = Offer::new(Client::handshake(Box::new(client), Hello::new()).await?)
+            .offer(mypkg.clone())
+            .await?
+            .negotiate()
+            .await?;
+        for file in state.wanted() {
+            state.send_file(file).await?;
+        }
+        state.done().await?;
+        drop(state);
+        // one session and the server is done
+        server_handle.await??;
+        let stored = hash_file(&file_path(&mypkg.files[0]), Digest::Sha256)?;
+        assert_eq!(stored.md5sum, mypkg.files[0].md5sum);
 
-        assert_eq!(original_crushingit.md5sum, blobfish_crushingit.md5sum);
-        assert_eq!(original_wombatchew.md5sum, blobfish_wombatchew.md5sum);
+        // a session that fails is reported back and ends the server with the error
+        let (client, server) = tokio::io::duplex(4 * BLOCK_SIZE);
+        let server_handle =
+            tokio::spawn(
+                async move { Server::serve_stream(Box::new(server), HashSet::new()).await },
+            );
+        let mut client = Client::handshake(Box::new(client), Hello::new()).await?;
+        client
+            .write(Done {
+                md5sum: "nope".into(),
+            })
+            .await?;
+        let e = client.read::<ListMyPkgAck>().await.err().unwrap();
+        assert!(e.downcast_ref::<PeerError>().is_some());
+        drop(client);
+        assert!(server_handle.await?.is_err());
         Ok(())
     }
 }
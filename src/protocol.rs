@@ -1,16 +1,21 @@
 use anyhow::{bail, Error};
 use chrono::Utc;
-use md5::{Digest, Md5};
+use md5::{Digest as _, Md5};
 use positioned_io::{ReadAt, WriteAt};
-use serde_bytes::ByteBuf;
 use serde_derive::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt;
 use std::io::{self, BufReader, Read};
 use std::os::unix::fs::OpenOptionsExt;
+use tokio_util::bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MyPkg {
     pub name: String,
-    pub md5sum: String, // sum of all md5sum in vec<file>
+    pub md5sum: String, // sum of all md5sum in vec<file>, in the digest of the package
+    #[serde(default)]
+    pub digest: Digest,
     // pub version: u64,
     // pub ephemeral: String,
     pub author: String,
@@ -24,15 +29,19 @@ pub struct MyPkg {
 }
 
 impl MyPkg {
-    pub fn new(name: String, paths: Vec<String>) -> Result<MyPkg, Error> {
-        let files: Vec<File> = paths.iter().map(|p| hash_file(p).unwrap()).collect();
-        let all_files_hasher = files.iter().fold(Md5::new(), |mut hasher, v| {
-            hasher.update(&v.md5sum);
+    pub fn new(name: String, paths: Vec<String>, digest: Digest) -> Result<MyPkg, Error> {
+        let files: Vec<File> = paths
+            .iter()
+            .map(|p| hash_file(p, digest))
+            .collect::<Result<_, _>>()?;
+        let all_files_hasher = files.iter().fold(digest.hasher(), |mut hasher, v| {
+            hasher.update(v.md5sum.as_bytes());
             hasher
         });
         Ok(MyPkg {
             name,
-            md5sum: format!("{:x}", all_files_hasher.finalize()),
+            md5sum: all_files_hasher.finalize(),
+            digest,
             // version: todo!(), // refactor
             // ephemeral: todo!(), // refactor
             author: "Joe".into(),
@@ -45,8 +54,127 @@ impl MyPkg {
             files,
         })
     }
-    pub fn load() {}
-    pub fn write() {}
+    // every sum of the package has to be a well formed sum in the digest of the package
+    pub fn check_digest(&self) -> Result<(), Error> {
+        if !self.digest.is_sum(&self.md5sum) {
+            bail!("{} is not a {} sum", self.md5sum, self.digest);
+        }
+        for file in &self.files {
+            if file.digest != self.digest {
+                bail!(
+                    "file {} uses {} but the package uses {}",
+                    file.md5sum,
+                    file.digest,
+                    self.digest
+                );
+            }
+            if !self.digest.is_sum(&file.md5sum)
+                || !file.pieces.iter().all(|p| self.digest.is_sum(p))
+            {
+                bail!("file {} has sums that are not {}", file.md5sum, self.digest);
+            }
+        }
+        Ok(())
+    }
+    pub fn load(path: &str) -> Result<MyPkg, Error> {
+        let raw = std::fs::read(path)?;
+        Ok(serde_bencode::from_bytes::<MyPkg>(&raw)?)
+    }
+    pub fn write(&self, path: &str) -> Result<(), Error> {
+        if let Some(parent) = std::path::Path::new(path).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_bencode::to_bytes(self)?)?;
+        Ok(())
+    }
+}
+
+// the algorithm the sums of a package and its files are computed with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Digest {
+    // manifests from before the digest was recorded are md5
+    #[default]
+    Md5,
+    Sha256,
+    Blake3,
+}
+
+pub enum Hasher {
+    Md5(Md5),
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Digest {
+    pub const ALL: [Digest; 3] = [Digest::Md5, Digest::Sha256, Digest::Blake3];
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            Digest::Md5 => Hasher::Md5(Md5::new()),
+            Digest::Sha256 => Hasher::Sha256(Sha256::new()),
+            Digest::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+    // the lowercase hex sum of data
+    pub fn sum(self, data: &[u8]) -> String {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+    // the length of a hex sum
+    pub fn hex_len(self) -> usize {
+        match self {
+            Digest::Md5 => 32,
+            Digest::Sha256 | Digest::Blake3 => 64,
+        }
+    }
+    pub fn is_sum(self, sum: &str) -> bool {
+        sum.len() == self.hex_len()
+            && sum
+                .chars()
+                .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Digest::Md5 => "md5",
+            Digest::Sha256 => "sha256",
+            Digest::Blake3 => "blake3",
+        };
+        f.write_str(name)
+    }
+}
+
+impl std::str::FromStr for Digest {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Digest::ALL.into_iter().find(|d| d.to_string() == s) {
+            Some(digest) => Ok(digest),
+            None => bail!("unknown digest {}, expected one of md5, sha256, blake3", s),
+        }
+    }
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(h) => h.update(data),
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+    pub fn finalize(self) -> String {
+        match self {
+            Hasher::Md5(h) => format!("{:x}", h.finalize()),
+            Hasher::Sha256(h) => format!("{:x}", h.finalize()),
+            Hasher::Blake3(h) => h.finalize().to_hex().to_string(),
+        }
+    }
 }
 
 pub const BLOCK_SIZE: usize = 16384;
@@ -55,34 +183,71 @@ pub const MSG_TYPE: usize = 2; // msg type is a u16 and represents the max numbe
 pub const HEADER_SIZE: usize = MSG_SIZE + MSG_TYPE;
 pub const BLOCK_SIZE_LESS_HEADER: usize = BLOCK_SIZE - HEADER_SIZE;
 
-pub fn hash_file(p: &str) -> Result<File, Error> {
+pub type PieceReader = Box<dyn Fn(u64, &mut [u8]) -> io::Result<usize> + Send>;
+pub type PieceWriter = Box<dyn FnMut(u64, &[u8]) -> io::Result<usize> + Send>;
+
+// the largest manifest accepted, about a 16 GiB package summed with sha256
+pub const MAX_MANIFEST_LEN: usize = 64 * 1024 * 1024;
+
+// how many rounds of resending bad pieces a file gets before the transfer is given up
+pub const MAX_RETRANSMITS: usize = 3;
+
+pub fn hash_file(p: &str, digest: Digest) -> Result<File, Error> {
     let f = std::fs::File::open(p).map_err(|e| {
-        println!("tried path {}", p);
+        eprintln!("tried path {}", p);
         dbg!(e)
     })?;
     let mut reader = BufReader::new(f);
     let mut buf = [0; BLOCK_SIZE];
-    let mut hasher = Md5::new();
+    let mut hasher = digest.hasher();
+    let mut pieces = vec![];
     let mut total_read = 0;
-    while let Ok(n) = reader.read(&mut buf) {
-        total_read += n;
+    loop {
+        // fill a whole piece so piece sums line up with BLOCK_SIZE boundaries
+        let mut n = 0;
+        while n < BLOCK_SIZE {
+            match reader.read(&mut buf[n..])? {
+                0 => break,
+                read => n += read,
+            }
+        }
         if n == 0 {
             break;
         }
+        total_read += n;
         hasher.update(&buf[..n]);
+        pieces.push(digest.sum(&buf[..n]));
     }
     Ok(File {
         path: p.to_string(),
         length: total_read as u64,
-        md5sum: format!("{:x}", hasher.finalize()),
+        md5sum: hasher.finalize(),
+        digest,
+        pieces,
     })
 }
 
+// collapse sorted piece numbers into half open ranges
+pub fn ranges(pieces: impl IntoIterator<Item = u64>) -> Vec<[u64; 2]> {
+    let mut ranges: Vec<[u64; 2]> = vec![];
+    for piece in pieces {
+        match ranges.last_mut() {
+            Some(last) if last[1] == piece => last[1] = piece + 1,
+            _ => ranges.push([piece, piece + 1]),
+        }
+    }
+    ranges
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct File {
     pub path: String,
-    pub length: u64, // byte len of file
-    pub md5sum: String,
+    pub length: u64,    // byte len of file
+    pub md5sum: String, // sum of the file in its digest, which also names it on disk
+    #[serde(default)]
+    pub digest: Digest,
+    #[serde(default)]
+    pub pieces: Vec<String>, // sum of every piece in order, empty for manifests from before
 }
 
 impl File {
@@ -93,34 +258,38 @@ impl File {
         }
         self.path.to_owned()
     }
+    // every piece is BLOCK_SIZE long except the last, an empty file has no pieces
     pub fn chunk_count(self) -> usize {
-        let block_size = BLOCK_SIZE as u64;
-        let mut s = self.length / block_size;
-        if self.length & block_size != 0 {
-            s += 1;
-        }
-        if s == 0 {
-            // tiny files are zero due to int division
-            return 1;
+        self.length.div_ceil(BLOCK_SIZE as u64) as usize
+    }
+    // the exact byte length of a piece, zero past the end of the file
+    pub fn piece_len(&self, piece: u64) -> usize {
+        let offset = piece.saturating_mul(BLOCK_SIZE as u64);
+        self.length.saturating_sub(offset).min(BLOCK_SIZE as u64) as usize
+    }
+    // check a received piece against its sum, a manifest without piece sums can't tell
+    pub fn verify_piece(&self, piece: u64, data: &[u8]) -> bool {
+        match self.pieces.get(piece as usize) {
+            Some(sum) => self.digest.sum(data) == *sum,
+            None => self.pieces.is_empty(),
         }
-        s as usize
     }
-    pub fn read_at(
-        self,
-    ) -> Result<Box<dyn Fn(u64, &mut [u8; BLOCK_SIZE]) -> io::Result<usize> + Send>, Error> {
-        let f = std::fs::File::open(self.path)?;
+    // the list of piece sums is either missing or has one sum per piece
+    pub fn has_valid_pieces(&self) -> bool {
+        self.pieces.is_empty() || self.pieces.len() == self.clone().chunk_count()
+    }
+    // the returned reader fills exactly piece_len bytes of buf and returns that length
+    pub fn read_at(self) -> Result<PieceReader, Error> {
+        let f = std::fs::File::open(&self.path)?;
         let block_size = BLOCK_SIZE as u64;
-        let capturing_closure =
-            move |p: u64, buf: &mut [u8; BLOCK_SIZE]| f.read_at(p * block_size, buf);
-        Ok(Box::new(capturing_closure)
-            as Box<
-                dyn Fn(u64, &mut [u8; BLOCK_SIZE]) -> io::Result<usize> + Send,
-            >)
-    }
-    pub fn write_at(
-        self,
-        path: String,
-    ) -> Result<Box<dyn FnMut(u64, &[u8]) -> io::Result<usize> + Send>, Error> {
+        let capturing_closure = move |p: u64, buf: &mut [u8]| {
+            let n = self.piece_len(p);
+            f.read_exact_at(p * block_size, &mut buf[..n])?;
+            Ok(n)
+        };
+        Ok(Box::new(capturing_closure) as PieceReader)
+    }
+    pub fn write_at(self, path: String) -> Result<PieceWriter, Error> {
         // Create the directory path if it doesn't exist
         if let Some(parent) = std::path::Path::new(&path).parent() {
             std::fs::create_dir_all(parent)?;
@@ -128,11 +297,12 @@ impl File {
         let mut f = std::fs::OpenOptions::new()
             .write(true)
             .create(true) // Create the file if it doesn't exist
+            .truncate(false) // pieces may arrive in any order, never clobber
             .mode(0o644) // Set file permissions
             .open(path)?;
         let block_size = BLOCK_SIZE as u64;
         let capturing_closure = move |p: u64, buf: &[u8]| f.write_at(p * block_size, buf);
-        Ok(Box::new(capturing_closure) as Box<dyn FnMut(u64, &[u8]) -> io::Result<usize> + Send>)
+        Ok(Box::new(capturing_closure) as PieceWriter)
     }
 }
 
@@ -145,12 +315,17 @@ pub struct MyPkgAck {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct NegotiateMyPkg {
     pub md5sum: String,
+    // the compressions the client can send pieces with, most preferred first
+    #[serde(default)]
+    pub compression: Vec<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct NegotiateMyPkgAck {
     pub md5sum: String,
     pub peers: Option<Vec<String>>,
+    // the compression chosen for this transfer, None sends pieces as they are
+    pub compression: Option<Compression>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -160,14 +335,187 @@ pub struct PieceExchange {
 }
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PieceExchangeAck {
-    // todo use pieces to signal a resume. for now this is always None
-    pub pieces: Option<[u64; 2]>,
+    // the ranges still missing when resuming a file, None means send every piece offered
+    pub pieces: Option<Vec<[u64; 2]>>,
 }
-#[derive(Clone, Debug, Serialize, Deserialize)]
+// how pieces may be compressed on the wire, they are always stored as they are
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    pub const ALL: [Compression; 2] = [Compression::Zstd, Compression::Lz4];
+
+    // the first of the peer's compressions we support, unknown names are skipped
+    pub fn choose(offered: &[String]) -> Option<Compression> {
+        offered.iter().find_map(|c| c.parse().ok())
+    }
+    fn id(self) -> u8 {
+        match self {
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+        }
+    }
+    fn from_id(id: u8) -> Option<Compression> {
+        Compression::ALL.into_iter().find(|c| c.id() == id)
+    }
+    pub fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::Zstd => zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+            Compression::Lz4 => Ok(lz4_flex::block::compress(data)),
+        }
+    }
+    // decompress data that has to come out exactly len bytes long
+    pub fn decompress(self, data: &[u8], len: usize) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![0; len];
+        let n = match self {
+            Compression::Zstd => zstd::bulk::decompress_to_buffer(data, &mut buf)?,
+            Compression::Lz4 => lz4_flex::block::decompress_into(data, &mut buf)?,
+        };
+        if n != len {
+            bail!(
+                "{} piece decompressed to {} bytes but should be {}",
+                self,
+                n,
+                len
+            );
+        }
+        Ok(buf)
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Compression::Zstd => "zstd",
+            Compression::Lz4 => "lz4",
+        };
+        f.write_str(name)
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Compression::ALL.into_iter().find(|c| c.to_string() == s) {
+            Some(compression) => Ok(compression),
+            None => bail!("unknown compression {}, expected one of zstd, lz4", s),
+        }
+    }
+}
+
+// pieces are not bencoded, they travel as a binary frame: the usual header with the length of
+// the data, then [u64 piece][u8 flags][u64 ack] and the data itself. the lowest bit of flags
+// says if ack is set, the bits above it which compression the data is in
+#[derive(Clone, Debug)]
 pub struct Piece {
     pub piece: u64,
     pub ack: Option<u64>,
-    pub data: ByteBuf,
+    pub compression: Option<Compression>,
+    pub data: Bytes,
+}
+
+pub const PIECE_HEADER_SIZE: usize = 8 + 1 + 8;
+
+impl Piece {
+    // read a piece of file straight into the buffer it is sent from
+    pub fn read(read_at: &PieceReader, file: &File, piece: u64) -> io::Result<Piece> {
+        let mut data = BytesMut::zeroed(file.piece_len(piece));
+        read_at(piece, &mut data)?;
+        Ok(Piece {
+            piece,
+            ack: None,
+            compression: None,
+            data: data.freeze(),
+        })
+    }
+    // compress the data of a piece, a piece that does not shrink is left as it is
+    pub fn compress(self, compression: Option<Compression>) -> io::Result<Piece> {
+        let Some(c) = compression else {
+            return Ok(self);
+        };
+        let compressed = c.compress(&self.data)?;
+        if compressed.len() >= self.data.len() {
+            return Ok(self);
+        }
+        Ok(Piece {
+            compression: Some(c),
+            data: compressed.into(),
+            ..self
+        })
+    }
+    // undo compress, a piece may only be compressed the way the transfer negotiated
+    pub fn decompress(self, negotiated: Option<Compression>, len: usize) -> Result<Piece, Error> {
+        let Some(c) = self.compression else {
+            return Ok(self);
+        };
+        if Some(c) != negotiated {
+            bail!(ErrorMsg::new(
+                ErrorMsg::UNEXPECTED,
+                format!(
+                    "piece {} is compressed with {} which was not negotiated",
+                    self.piece, c
+                )
+            ));
+        }
+        let data = c.decompress(&self.data, len).map_err(|e| {
+            ErrorMsg::new(ErrorMsg::CORRUPT, format!("piece {}: {}", self.piece, e))
+        })?;
+        Ok(Piece {
+            compression: None,
+            data: data.into(),
+            ..self
+        })
+    }
+    // the piece header and data of a piece frame
+    pub fn decode(mut raw_msg: Bytes) -> Result<Piece, Error> {
+        if raw_msg.len() < PIECE_HEADER_SIZE {
+            bail!(ErrorMsg::new(
+                ErrorMsg::MALFORMED,
+                format!("piece frame of {} bytes has no header", raw_msg.len())
+            ));
+        }
+        let piece = raw_msg.get_u64();
+        let flags = raw_msg.get_u8();
+        let ack = raw_msg.get_u64();
+        let compression = match flags >> 1 {
+            0 => None,
+            id => match Compression::from_id(id) {
+                Some(c) => Some(c),
+                None => bail!(ErrorMsg::new(
+                    ErrorMsg::MALFORMED,
+                    format!("piece {} has unknown flags {:#x}", piece, flags)
+                )),
+            },
+        };
+        Ok(Piece {
+            piece,
+            ack: (flags & 1 != 0).then_some(ack),
+            compression,
+            data: raw_msg,
+        })
+    }
+    fn encode(&self, dst: &mut BytesMut) -> Result<(), Error> {
+        if self.data.len() > BLOCK_SIZE {
+            bail!(
+                "piece {} is {} bytes, longer than a block",
+                self.piece,
+                self.data.len()
+            );
+        }
+        dst.reserve(HEADER_SIZE + PIECE_HEADER_SIZE + self.data.len());
+        dst.put_u16(self.data.len() as u16);
+        dst.put_u16(MessageType::PIECE);
+        dst.put_u64(self.piece);
+        let compression = self.compression.map(|c| c.id()).unwrap_or_default();
+        dst.put_u8(compression << 1 | self.ack.is_some() as u8);
+        dst.put_u64(self.ack.unwrap_or_default());
+        dst.put_slice(&self.data);
+        Ok(())
+    }
 }
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PieceAck {
@@ -179,6 +527,193 @@ pub struct Done {
     pub md5sum: String,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FetchMyPkg {
+    pub md5sum: Option<String>,
+    pub name: Option<String>,
+    // the compressions the client can receive pieces with, most preferred first
+    #[serde(default)]
+    pub compression: Vec<String>,
+}
+
+impl FetchMyPkg {
+    // a query that looks like a sum in any digest is treated as one, anything else is a package name
+    pub fn new(query: String) -> Self {
+        if Digest::ALL.iter().any(|d| d.is_sum(&query)) {
+            FetchMyPkg {
+                md5sum: Some(query),
+                name: None,
+                compression: vec![],
+            }
+        } else {
+            FetchMyPkg {
+                md5sum: None,
+                name: Some(query),
+                compression: vec![],
+            }
+        }
+    }
+    pub fn with_compression(mut self, compression: &[Compression]) -> Self {
+        self.compression = compression.iter().map(|c| c.to_string()).collect();
+        self
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FetchMyPkgAck {
+    // None when the server does not hold a matching package
+    pub mypkg: Option<MyPkg>,
+    // the compression chosen for this transfer, None sends pieces as they are
+    pub compression: Option<Compression>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ListMyPkg {
+    pub name: Option<String>,
+    pub tags: Vec<String>, // a package must carry every tag to match
+    pub offset: u64,
+    pub limit: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ListMyPkgAck {
+    pub total: u64, // number of matches before paging
+    pub mypkgs: Vec<MyPkg>,
+}
+
+// the protocol version this build speaks, and the oldest one it still understands
+pub const PROTOCOL_VERSION: u64 = 2;
+pub const MIN_PROTOCOL_VERSION: u64 = 2;
+
+// optional features a peer may offer in its hello, a session uses those both sides offer
+pub const FEATURE_RESUME: &str = "resume";
+
+// the first message on every connection, sent by the client
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u64,
+    pub min_version: u64,
+    pub message_types: Vec<u16>,
+    pub features: Vec<String>,
+}
+
+// the server's hello, its features are the ones both sides offered
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HelloAck {
+    pub version: u64,
+    pub min_version: u64,
+    pub message_types: Vec<u16>,
+    pub features: Vec<String>,
+}
+
+impl Hello {
+    pub fn new() -> Self {
+        let mut features = vec![FEATURE_RESUME.to_string()];
+        features.extend(Digest::ALL.iter().map(|d| format!("digest:{}", d)));
+        features.extend(
+            Compression::ALL
+                .iter()
+                .map(|c| format!("compression:{}", c)),
+        );
+        Hello {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            message_types: MessageType::MESSAGE_TYPES.to_vec(),
+            features,
+        }
+    }
+    // refuse a peer that can't speak a version we understand
+    pub fn check_version(&self, version: u64, min_version: u64) -> Result<u64, Error> {
+        if version < self.min_version || self.version < min_version {
+            bail!(ErrorMsg::new(
+                ErrorMsg::INCOMPATIBLE,
+                format!(
+                    "incompatible protocol version, peer speaks {} to {} but we speak {} to {}",
+                    min_version, version, self.min_version, self.version
+                )
+            ));
+        }
+        Ok(version.min(self.version))
+    }
+    // answer a client's hello with what both sides support
+    pub fn ack(&self, peer: &Hello) -> HelloAck {
+        HelloAck {
+            version: self.version.min(peer.version),
+            min_version: self.min_version,
+            message_types: self.message_types.clone(),
+            features: self
+                .features
+                .iter()
+                .filter(|f| peer.features.contains(f))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+impl Default for Hello {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// tells the peer why the session is about to end, either side may send it at any point
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ErrorMsg {
+    pub code: u16,
+    pub message: String,
+}
+
+impl ErrorMsg {
+    pub const MALFORMED: u16 = 1; // a message could not be decoded
+    pub const UNEXPECTED: u16 = 2; // a message does not fit the state of the session
+    pub const INCOMPATIBLE: u16 = 3; // the peers don't share a protocol version
+    pub const CORRUPT: u16 = 4; // received data does not match its sum
+    pub const INTERNAL: u16 = 5; // anything else that went wrong on the sending side
+
+    pub fn new(code: u16, message: impl Into<String>) -> Self {
+        ErrorMsg {
+            code,
+            message: message.into(),
+        }
+    }
+    // what to tell the peer about a failed session, nothing if the peer is the one who failed
+    pub fn from_error(e: &Error) -> Option<ErrorMsg> {
+        if e.downcast_ref::<PeerError>().is_some() {
+            return None;
+        }
+        if let Some(msg) = e.downcast_ref::<ErrorMsg>() {
+            return Some(msg.clone());
+        }
+        let code = if e.downcast_ref::<serde_bencode::Error>().is_some() {
+            ErrorMsg::MALFORMED
+        } else {
+            ErrorMsg::INTERNAL
+        };
+        Some(ErrorMsg::new(code, e.to_string()))
+    }
+}
+
+impl fmt::Display for ErrorMsg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (error code {})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for ErrorMsg {}
+
+// an error message received from the peer
+#[derive(Clone, Debug)]
+pub struct PeerError(pub ErrorMsg);
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "peer failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for PeerError {}
+
 pub enum MessageType {
     MyPkg(MyPkg),
     File(File),
@@ -190,10 +725,34 @@ pub enum MessageType {
     Piece(Piece),
     PieceAck(PieceAck),
     Done(Done),
+    FetchMyPkg(FetchMyPkg),
+    FetchMyPkgAck(FetchMyPkgAck),
+    ListMyPkg(ListMyPkg),
+    ListMyPkgAck(ListMyPkgAck),
+    Hello(Hello),
+    HelloAck(HelloAck),
+    Error(ErrorMsg),
 }
 impl MessageType {
+    pub const MESSAGE_TYPES: [u16; 17] = [
+        10, 20, 30, 40, 50, 60, 70, 80, 90, 100, 110, 120, 130, 140, 150, 160, 170,
+    ];
+    pub const PIECE: u16 = 80;
+    pub const ERROR: u16 = 170;
     pub fn is_valid_message_type(value: u16) -> bool {
-        matches!(value, 10 | 20 | 30 | 40 | 50 | 60 | 70 | 80 | 90 | 100)
+        Self::MESSAGE_TYPES.contains(&value)
+    }
+    // the longest a message of a type may be, so a peer can't make us buffer without end
+    pub fn max_len(value: u16) -> usize {
+        match value {
+            // manifests grow with the files of a package and their piece sums
+            10 | 20 | 30 | 60 | 120 | 140 => MAX_MANIFEST_LEN,
+            Self::PIECE => PIECE_HEADER_SIZE + BLOCK_SIZE,
+            // ranges of pieces, at worst every other piece of a large file
+            70 => 1024 * 1024,
+            50 | 130 | 150 | 160 | Self::ERROR => 64 * 1024,
+            _ => 4 * 1024,
+        }
     }
     pub fn message_type(&self) -> u16 {
         match self {
@@ -204,9 +763,16 @@ impl MessageType {
             MessageType::NegotiateMyPkgAck(_) => 50,
             MessageType::PieceExchange(_) => 60,
             MessageType::PieceExchangeAck(_) => 70,
-            MessageType::Piece(_) => 80,
+            MessageType::Piece(_) => Self::PIECE,
             MessageType::PieceAck(_) => 90,
             MessageType::Done(_) => 100,
+            MessageType::FetchMyPkg(_) => 110,
+            MessageType::FetchMyPkgAck(_) => 120,
+            MessageType::ListMyPkg(_) => 130,
+            MessageType::ListMyPkgAck(_) => 140,
+            MessageType::Hello(_) => 150,
+            MessageType::HelloAck(_) => 160,
+            MessageType::Error(_) => Self::ERROR,
         }
     }
 
@@ -219,13 +785,22 @@ impl MessageType {
             MessageType::NegotiateMyPkgAck(inner) => serde_bencode::to_bytes(inner),
             MessageType::PieceExchange(inner) => serde_bencode::to_bytes(inner),
             MessageType::PieceExchangeAck(inner) => serde_bencode::to_bytes(inner),
-            MessageType::Piece(inner) => serde_bencode::to_bytes(inner),
+            MessageType::Piece(_) => Err(serde_bencode::Error::Custom(
+                "pieces are sent as binary frames".into(),
+            )),
             MessageType::PieceAck(inner) => serde_bencode::to_bytes(inner),
             MessageType::Done(inner) => serde_bencode::to_bytes(inner),
+            MessageType::FetchMyPkg(inner) => serde_bencode::to_bytes(inner),
+            MessageType::FetchMyPkgAck(inner) => serde_bencode::to_bytes(inner),
+            MessageType::ListMyPkg(inner) => serde_bencode::to_bytes(inner),
+            MessageType::ListMyPkgAck(inner) => serde_bencode::to_bytes(inner),
+            MessageType::Hello(inner) => serde_bencode::to_bytes(inner),
+            MessageType::HelloAck(inner) => serde_bencode::to_bytes(inner),
+            MessageType::Error(inner) => serde_bencode::to_bytes(inner),
         }
         // .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
     }
-    pub fn deserialize(v: u16, raw_msg: &Vec<u8>) -> Result<MessageType, Error> {
+    pub fn deserialize(v: u16, raw_msg: &[u8]) -> Result<MessageType, Error> {
         match v {
             10 => Ok(MessageType::MyPkg(serde_bencode::from_bytes::<MyPkg>(
                 raw_msg,
@@ -248,15 +823,36 @@ impl MessageType {
             70 => Ok(MessageType::PieceExchangeAck(serde_bencode::from_bytes::<
                 PieceExchangeAck,
             >(raw_msg)?)),
-            80 => Ok(MessageType::Piece(serde_bencode::from_bytes::<Piece>(
+            Self::PIECE => Ok(MessageType::Piece(Piece::decode(Bytes::copy_from_slice(
                 raw_msg,
-            )?)),
+            ))?)),
             90 => Ok(MessageType::PieceAck(
                 serde_bencode::from_bytes::<PieceAck>(raw_msg)?,
             )),
             100 => Ok(MessageType::Done(serde_bencode::from_bytes::<Done>(
                 raw_msg,
             )?)),
+            110 => Ok(MessageType::FetchMyPkg(serde_bencode::from_bytes::<
+                FetchMyPkg,
+            >(raw_msg)?)),
+            120 => Ok(MessageType::FetchMyPkgAck(serde_bencode::from_bytes::<
+                FetchMyPkgAck,
+            >(raw_msg)?)),
+            130 => Ok(MessageType::ListMyPkg(serde_bencode::from_bytes::<
+                ListMyPkg,
+            >(raw_msg)?)),
+            140 => Ok(MessageType::ListMyPkgAck(serde_bencode::from_bytes::<
+                ListMyPkgAck,
+            >(raw_msg)?)),
+            150 => Ok(MessageType::Hello(serde_bencode::from_bytes::<Hello>(
+                raw_msg,
+            )?)),
+            160 => Ok(MessageType::HelloAck(
+                serde_bencode::from_bytes::<HelloAck>(raw_msg)?,
+            )),
+            Self::ERROR => Ok(MessageType::Error(serde_bencode::from_bytes::<ErrorMsg>(
+                raw_msg,
+            )?)),
             _ => bail!("invalid message type {}", v),
         }
         // .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
@@ -299,10 +895,160 @@ impl MessageType {
 //     PieceExchangeAck
 // );
 
+// a whole message as read off the wire, its frames joined
+#[derive(Debug)]
+pub struct ReadResult {
+    pub message_type: u16,
+    pub raw_msg: Bytes,
+}
+
+// frames messages for both sides of a connection. every frame is a [u16 len][u16 type] header
+// and at most BLOCK_SIZE_LESS_HEADER bytes, a full frame means the message continues in the next
+#[derive(Default)]
+pub struct Codec {
+    // the frames of a message read so far, and its type
+    raw_msg: BytesMut,
+    message_type: Option<u16>,
+}
+
+impl Decoder for Codec {
+    type Item = ReadResult;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<ReadResult>, Error> {
+        loop {
+            if src.len() < HEADER_SIZE {
+                src.reserve(BLOCK_SIZE);
+                return Ok(None);
+            }
+            let prefix_length = u16::from_be_bytes([src[0], src[1]]) as usize;
+            let message_type = u16::from_be_bytes([src[2], src[3]]);
+            if !MessageType::is_valid_message_type(message_type) {
+                bail!(ErrorMsg::new(
+                    ErrorMsg::MALFORMED,
+                    format!("invalid message type {}", message_type)
+                ));
+            }
+            // a message continues with frames of its own type, up to the limit of that type
+            if let Some(continued) = self.message_type {
+                if continued != message_type {
+                    bail!(ErrorMsg::new(
+                        ErrorMsg::MALFORMED,
+                        format!(
+                            "message of type {} continued with a frame of type {}",
+                            continued, message_type
+                        )
+                    ));
+                }
+            }
+            // a piece frame is never continued, its length is that of the data after the piece header
+            if message_type == MessageType::PIECE {
+                if prefix_length > BLOCK_SIZE {
+                    bail!(ErrorMsg::new(
+                        ErrorMsg::MALFORMED,
+                        format!("piece of {} bytes is longer than a block", prefix_length)
+                    ));
+                }
+                let frame_len = HEADER_SIZE + PIECE_HEADER_SIZE + prefix_length;
+                if src.len() < frame_len {
+                    src.reserve(frame_len - src.len());
+                    return Ok(None);
+                }
+                src.advance(HEADER_SIZE);
+                return Ok(Some(ReadResult {
+                    message_type,
+                    raw_msg: src.split_to(PIECE_HEADER_SIZE + prefix_length).freeze(),
+                }));
+            }
+            if prefix_length > BLOCK_SIZE_LESS_HEADER {
+                bail!(ErrorMsg::new(
+                    ErrorMsg::MALFORMED,
+                    format!("invalid frame length {}", prefix_length)
+                ));
+            }
+            let max_len = MessageType::max_len(message_type);
+            if self.raw_msg.len() + prefix_length > max_len {
+                bail!(ErrorMsg::new(
+                    ErrorMsg::MALFORMED,
+                    format!(
+                        "message of type {} is longer than its limit of {} bytes",
+                        message_type, max_len
+                    )
+                ));
+            }
+            if src.len() < HEADER_SIZE + prefix_length {
+                src.reserve(HEADER_SIZE + prefix_length - src.len());
+                return Ok(None);
+            }
+            src.advance(HEADER_SIZE);
+            if prefix_length < BLOCK_SIZE_LESS_HEADER {
+                self.message_type = None;
+                // a message of a single frame is handed out without copying it
+                let raw_msg = if self.raw_msg.is_empty() {
+                    src.split_to(prefix_length).freeze()
+                } else {
+                    self.raw_msg.extend_from_slice(&src.split_to(prefix_length));
+                    self.raw_msg.split().freeze()
+                };
+                // an error from the peer ends the session, surface it as such
+                if message_type == MessageType::ERROR {
+                    let msg: ErrorMsg = serde_bencode::from_bytes(&raw_msg)?;
+                    return Err(PeerError(msg).into());
+                }
+                return Ok(Some(ReadResult {
+                    message_type,
+                    raw_msg,
+                }));
+            }
+            self.raw_msg.extend_from_slice(&src.split_to(prefix_length));
+            self.message_type = Some(message_type);
+        }
+    }
+}
+
+impl Encoder<&MessageType> for Codec {
+    type Error = Error;
+
+    fn encode(&mut self, t: &MessageType, dst: &mut BytesMut) -> Result<(), Error> {
+        if let MessageType::Piece(piece) = t {
+            return piece.encode(dst);
+        }
+        let b = t.serialize_inner()?;
+        let message_type = t.message_type();
+        let max_len = MessageType::max_len(message_type);
+        if b.len() > max_len {
+            bail!(
+                "message of type {} is {} bytes, longer than its limit of {} bytes",
+                message_type,
+                b.len(),
+                max_len
+            );
+        }
+        let mut chunks: Vec<&[u8]> = b.chunks(BLOCK_SIZE_LESS_HEADER).collect();
+        // a message that ends on a full frame needs an empty one to say it is done
+        if b.len().is_multiple_of(BLOCK_SIZE_LESS_HEADER) {
+            chunks.push(&[]);
+        }
+        dst.reserve(b.len() + chunks.len() * HEADER_SIZE);
+        for chunk in chunks {
+            dst.put_u16(chunk.len() as u16);
+            dst.put_u16(message_type);
+            dst.put_slice(chunk);
+        }
+        Ok(())
+    }
+}
+
 pub trait ToMessageType {
     fn to_message_type(self) -> MessageType;
 }
 
+impl ToMessageType for ErrorMsg {
+    fn to_message_type(self) -> MessageType {
+        MessageType::Error(self)
+    }
+}
+
 macro_rules! impl_to_message_type {
     ($($variant:ident),*) => {
         $(
@@ -317,7 +1063,13 @@ macro_rules! impl_to_message_type {
 
 impl_to_message_type!(
     Done,
+    FetchMyPkg,
+    FetchMyPkgAck,
     File,
+    Hello,
+    HelloAck,
+    ListMyPkg,
+    ListMyPkgAck,
     MyPkg,
     MyPkgAck,
     NegotiateMyPkg,
@@ -327,3 +1079,282 @@ impl_to_message_type!(
     PieceExchange,
     PieceExchangeAck
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(length: u64) -> File {
+        File {
+            path: "geometry".into(),
+            length,
+            md5sum: "".into(),
+            digest: Digest::default(),
+            pieces: vec![],
+        }
+    }
+
+    #[test]
+    fn test_piece_geometry() {
+        let block = BLOCK_SIZE as u64;
+        let cases: [(u64, usize, usize); 6] = [
+            (0, 0, 0),
+            (1, 1, 1),
+            (block, 1, BLOCK_SIZE),
+            (block + 1, 2, 1),
+            (2 * block, 2, BLOCK_SIZE),
+            (5 * 1024 * 1024 + 3, 321, 3),
+        ];
+        for (length, pieces, last) in cases {
+            let f = file(length);
+            assert_eq!(f.clone().chunk_count(), pieces, "pieces for {}", length);
+            if pieces > 0 {
+                assert_eq!(f.piece_len(pieces as u64 - 1), last, "last for {}", length);
+            }
+            assert_eq!(f.piece_len(pieces as u64), 0, "past the end for {}", length);
+            let total: usize = (0..pieces as u64).map(|p| f.piece_len(p)).sum();
+            assert_eq!(total as u64, length);
+        }
+    }
+
+    #[test]
+    fn test_piece_sums() -> Result<(), Error> {
+        let path = std::env::temp_dir().join("blobfish-test-piece-sums.bin");
+        let data: Vec<u8> = (0..2 * BLOCK_SIZE + 10).map(|i| (i % 253) as u8).collect();
+        std::fs::write(&path, &data)?;
+        let f = hash_file(&path.to_string_lossy(), Digest::Sha256)?;
+        assert_eq!(f.pieces.len(), 3);
+        assert!(f.has_valid_pieces());
+        assert!(f.verify_piece(0, &data[..BLOCK_SIZE]));
+        assert!(f.verify_piece(2, &data[2 * BLOCK_SIZE..]));
+        assert!(!f.verify_piece(1, &data[..BLOCK_SIZE]));
+        assert!(!f.verify_piece(3, &[]));
+        assert_eq!(ranges([1, 2, 3, 7, 9, 10]), vec![[1, 4], [7, 8], [9, 11]]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_digests() -> Result<(), Error> {
+        // the sums of the empty input
+        let empty = [
+            (Digest::Md5, "d41d8cd98f00b204e9800998ecf8427e"),
+            (
+                Digest::Sha256,
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+            (
+                Digest::Blake3,
+                "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+            ),
+        ];
+        for (digest, sum) in empty {
+            assert_eq!(digest.sum(&[]), sum);
+            assert!(digest.is_sum(sum));
+            assert_eq!(digest.to_string().parse::<Digest>()?, digest);
+        }
+        assert!("sha1".parse::<Digest>().is_err());
+
+        // a manifest from before the digest was recorded is md5
+        let mut f = file(0);
+        f.digest = Digest::Blake3;
+        let raw = serde_bencode::to_bytes(&f)?;
+        assert_eq!(
+            serde_bencode::from_bytes::<File>(&raw)?.digest,
+            Digest::Blake3
+        );
+        let legacy = b"d6:lengthi0e6:md5sum0:4:path8:geometrye";
+        assert_eq!(
+            serde_bencode::from_bytes::<File>(legacy)?.digest,
+            Digest::Md5
+        );
+        Ok(())
+    }
+
+    // a message that is exactly len bytes once serialized
+    fn message_of_len(len: usize) -> ListMyPkg {
+        let mut name = len;
+        loop {
+            let m = ListMyPkg {
+                name: Some("x".repeat(name)),
+                ..Default::default()
+            };
+            let n = serde_bencode::to_bytes(&m).unwrap().len();
+            if n == len {
+                return m;
+            }
+            name = name + len - n;
+        }
+    }
+
+    #[test]
+    fn test_codec() -> Result<(), Error> {
+        let mut codec = Codec::default();
+        let mut wire = BytesMut::new();
+        // one frame, a message that ends on a full frame, and one spread over three frames
+        let lens = [
+            64,
+            BLOCK_SIZE_LESS_HEADER,
+            2 * BLOCK_SIZE_LESS_HEADER,
+            2 * BLOCK_SIZE_LESS_HEADER + 1,
+        ];
+        for len in lens {
+            codec.encode(&message_of_len(len).to_message_type(), &mut wire)?;
+        }
+        // pieces travel in their own frame, a full one and the short last one of a file
+        for (piece, ack, len) in [(3, Some(2), BLOCK_SIZE), (4, None, 5)] {
+            let p = Piece {
+                piece,
+                ack,
+                compression: None,
+                data: Bytes::from(vec![piece as u8; len]),
+            };
+            codec.encode(&p.to_message_type(), &mut wire)?;
+        }
+        // the bytes may arrive in any split
+        let mut src = BytesMut::new();
+        let mut decoded = vec![];
+        for chunk in wire.chunks(1000) {
+            src.extend_from_slice(chunk);
+            while let Some(r) = codec.decode(&mut src)? {
+                decoded.push(r);
+            }
+        }
+        assert!(src.is_empty());
+        assert_eq!(decoded.len(), lens.len() + 2);
+        for (r, len) in decoded.iter().zip(lens) {
+            assert_eq!(r.message_type, 130);
+            assert_eq!(r.raw_msg.len(), len);
+        }
+        let pieces: Vec<Piece> = decoded
+            .drain(lens.len()..)
+            .map(|r| Piece::decode(r.raw_msg))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(pieces[0].piece, 3);
+        assert_eq!(pieces[0].ack, Some(2));
+        assert_eq!(pieces[0].data, vec![3; BLOCK_SIZE]);
+        assert_eq!(pieces[1].ack, None);
+        assert_eq!(pieces[1].data, vec![4; 5]);
+
+        // an error from the peer is surfaced as one
+        let mut src = BytesMut::new();
+        let msg = ErrorMsg::new(ErrorMsg::CORRUPT, "bad piece");
+        codec.encode(&msg.to_message_type(), &mut src)?;
+        let e = codec.decode(&mut src).err().unwrap();
+        assert_eq!(
+            e.downcast_ref::<PeerError>().unwrap().0.code,
+            ErrorMsg::CORRUPT
+        );
+
+        // garbage headers are refused
+        let mut src = BytesMut::from(&[0xff, 0xff, 0, 80][..]);
+        assert!(codec.decode(&mut src).is_err());
+        let mut src = BytesMut::from(&[0, 1, 0, 81, 0][..]);
+        assert!(codec.decode(&mut src).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression() -> Result<(), Error> {
+        let text = Bytes::from("blobfish ".repeat(BLOCK_SIZE / 9));
+        let mut seed = 7u64;
+        let noise: Bytes = (0..BLOCK_SIZE)
+            .map(|_| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (seed >> 56) as u8
+            })
+            .collect();
+        for c in Compression::ALL {
+            assert_eq!(c.to_string().parse::<Compression>()?, c);
+            let p = Piece {
+                piece: 1,
+                ack: Some(0),
+                compression: None,
+                data: text.clone(),
+            };
+            let compressed = p.compress(Some(c))?;
+            assert_eq!(compressed.compression, Some(c));
+            assert!(compressed.data.len() < text.len());
+
+            // the compression survives the wire next to the ack
+            let mut wire = BytesMut::new();
+            Codec::default().encode(&compressed.to_message_type(), &mut wire)?;
+            let r = Codec::default().decode(&mut wire)?.unwrap();
+            let decoded = Piece::decode(r.raw_msg)?;
+            assert_eq!(decoded.compression, Some(c));
+            assert_eq!(decoded.ack, Some(0));
+            let p = decoded.decompress(Some(c), text.len())?;
+            assert_eq!(p.compression, None);
+            assert_eq!(p.data, text);
+
+            // data that does not shrink goes as it is
+            let p = Piece {
+                piece: 2,
+                ack: None,
+                compression: None,
+                data: noise.clone(),
+            };
+            let p = p.compress(Some(c))?;
+            assert_eq!(p.compression, None);
+            assert_eq!(p.data, noise);
+        }
+
+        // a compression that was not negotiated or that does not unpack is refused
+        let p = Piece {
+            piece: 3,
+            ack: None,
+            compression: None,
+            data: text.clone(),
+        }
+        .compress(Some(Compression::Zstd))?;
+        let e = p.clone().decompress(Some(Compression::Lz4), text.len());
+        let msg = e.unwrap_err().downcast::<ErrorMsg>()?;
+        assert_eq!(msg.code, ErrorMsg::UNEXPECTED);
+        let e = p.decompress(Some(Compression::Zstd), text.len() - 1);
+        let msg = e.unwrap_err().downcast::<ErrorMsg>()?;
+        assert_eq!(msg.code, ErrorMsg::CORRUPT);
+
+        assert_eq!(
+            Compression::choose(&["brotli".into(), "lz4".into()]),
+            Some(Compression::Lz4)
+        );
+        assert_eq!(Compression::choose(&[]), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_codec_limits() -> Result<(), Error> {
+        let frame = |message_type: u16| {
+            let mut f = BytesMut::new();
+            f.put_u16(BLOCK_SIZE_LESS_HEADER as u16);
+            f.put_u16(message_type);
+            f.put_bytes(0, BLOCK_SIZE_LESS_HEADER);
+            f
+        };
+        let code = |e: Error| e.downcast_ref::<ErrorMsg>().map(|m| m.code);
+
+        // a hello that never ends is cut off at its limit
+        let mut codec = Codec::default();
+        let mut src = BytesMut::new();
+        for _ in 0..5 {
+            src.extend_from_slice(&frame(150));
+        }
+        let e = codec.decode(&mut src).err().unwrap();
+        assert_eq!(code(e), Some(ErrorMsg::MALFORMED));
+
+        // a message may not change its type half way
+        let mut codec = Codec::default();
+        let mut src = frame(130);
+        src.extend_from_slice(&frame(140));
+        let e = codec.decode(&mut src).err().unwrap();
+        assert_eq!(code(e), Some(ErrorMsg::MALFORMED));
+
+        // and we don't send what the peer would refuse
+        let hello = Hello {
+            features: vec!["x".repeat(1024); 64],
+            ..Hello::new()
+        };
+        let mut dst = BytesMut::new();
+        assert!(codec.encode(&hello.to_message_type(), &mut dst).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+use anyhow::Result;
+
+use crate::protocol::ranges;
+
+// which pieces of a partially received file are already on disk, one bit per piece
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bitfield {
+    pieces: u64,
+    bits: Vec<u8>,
+}
+
+impl Bitfield {
+    pub fn new(pieces: u64) -> Bitfield {
+        Bitfield {
+            pieces,
+            bits: vec![0; pieces.div_ceil(8) as usize],
+        }
+    }
+    // load a saved bitfield, a missing or mis-sized one starts over from nothing
+    pub fn load(path: &str, pieces: u64) -> Result<Bitfield> {
+        match std::fs::read(path) {
+            Ok(bits) if bits.len() as u64 == pieces.div_ceil(8) => Ok(Bitfield { pieces, bits }),
+            Ok(_) => Ok(Bitfield::new(pieces)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Bitfield::new(pieces)),
+            Err(e) => Err(e.into()),
+        }
+    }
+    // write the bitfield next to its data, the data must be synced first so no bit outlives it
+    pub fn save(&self, path: &str, data: &str) -> Result<()> {
+        std::fs::File::open(data)?.sync_data()?;
+        if let Some(parent) = std::path::Path::new(path).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = format!("{}.tmp", path);
+        std::fs::write(&tmp, &self.bits)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+    pub fn remove(path: &str) -> Result<()> {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+    pub fn has(&self, piece: u64) -> bool {
+        piece < self.pieces && self.bits[(piece / 8) as usize] & (1 << (piece % 8)) != 0
+    }
+    pub fn set(&mut self, piece: u64) {
+        if piece < self.pieces {
+            self.bits[(piece / 8) as usize] |= 1 << (piece % 8);
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|b| *b == 0)
+    }
+    // the half open ranges of pieces within [start, end) that are not on disk yet
+    pub fn missing(&self, pieces: [u64; 2]) -> Vec<[u64; 2]> {
+        let [start, end] = pieces;
+        ranges((start..end).filter(|piece| !self.has(*piece)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_ranges() {
+        let mut bitfield = Bitfield::new(10);
+        assert_eq!(bitfield.missing([0, 10]), vec![[0, 10]]);
+        for piece in [0, 1, 4, 9] {
+            bitfield.set(piece);
+        }
+        assert_eq!(bitfield.missing([0, 10]), vec![[2, 4], [5, 9]]);
+        assert_eq!(bitfield.missing([3, 6]), vec![[3, 4], [5, 6]]);
+        for piece in 0..10 {
+            bitfield.set(piece);
+        }
+        assert!(bitfield.missing([0, 10]).is_empty());
+    }
+}
@@ -0,0 +1,150 @@
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{
+    protocol::{FetchMyPkg, File, ListMyPkg, ListMyPkgAck, MyPkg},
+    server::server::file_path,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PkgState {
+    // the manifest is known but not every file is on disk yet
+    Partial,
+    // every file of the manifest is stored, the package can be served
+    Complete,
+}
+
+// one record of the index, persisted as bencode next to the other records
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub mypkg: MyPkg,
+    pub state: PkgState,
+}
+
+impl Entry {
+    // a complete entry is only trusted if every file is still on disk with its full length
+    fn verify(&mut self) {
+        if self.state != PkgState::Complete {
+            return;
+        }
+        for file in &self.mypkg.files {
+            let path = file_path(file);
+            let length = std::fs::metadata(&path).map(|m| m.len());
+            if length.ok() != Some(file.length) {
+                eprintln!(
+                    "package {} is missing {}, marking it partial",
+                    self.mypkg.md5sum, path
+                );
+                self.state = PkgState::Partial;
+                return;
+            }
+        }
+    }
+}
+
+// the durable index of every package the server holds, keyed by the package md5sum
+pub struct Catalog {
+    dir: String,
+    entries: HashMap<String, Entry>,
+}
+
+impl Catalog {
+    // rebuild the index from the records in dir, verifying each against the data tree
+    pub fn open(dir: &str) -> Result<Catalog> {
+        let mut catalog = Catalog {
+            dir: dir.to_owned(),
+            entries: HashMap::new(),
+        };
+        let records = match std::fs::read_dir(dir) {
+            Ok(records) => records,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(catalog),
+            Err(e) => return Err(e.into()),
+        };
+        for record in records {
+            let path = record?.path();
+            let entry = std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|raw| Ok(serde_bencode::from_bytes::<Entry>(&raw)?));
+            match entry {
+                Ok(mut entry) => {
+                    entry.verify();
+                    catalog.entries.insert(entry.mypkg.md5sum.to_owned(), entry);
+                }
+                Err(e) => eprintln!("skipping unreadable index record {:?}: {:?}", path, e),
+            }
+        }
+        Ok(catalog)
+    }
+    // record the package in state, returns true if it was not complete before
+    pub fn set(&mut self, mypkg: MyPkg, state: PkgState) -> Result<bool> {
+        let was_complete = self.get(&mypkg.md5sum).is_some();
+        let entry = Entry { mypkg, state };
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(
+            format!("{}/{}", self.dir, entry.mypkg.md5sum),
+            serde_bencode::to_bytes(&entry)?,
+        )?;
+        self.entries.insert(entry.mypkg.md5sum.to_owned(), entry);
+        Ok(!was_complete && state == PkgState::Complete)
+    }
+    // a package only counts as held once it is complete
+    pub fn get(&self, md5sum: &str) -> Option<&MyPkg> {
+        self.entries
+            .get(md5sum)
+            .filter(|entry| entry.state == PkgState::Complete)
+            .map(|entry| &entry.mypkg)
+    }
+    fn complete(&self) -> impl Iterator<Item = &MyPkg> {
+        self.entries
+            .values()
+            .filter(|entry| entry.state == PkgState::Complete)
+            .map(|entry| &entry.mypkg)
+    }
+    // any stored file with this md5sum, whichever package it came with
+    pub fn file(&self, md5sum: &str) -> Option<&File> {
+        self.complete()
+            .flat_map(|mypkg| mypkg.files.iter())
+            .find(|file| file.md5sum == md5sum)
+    }
+    // find a package by md5sum, or the most recently built one by name
+    pub fn lookup(&self, query: &FetchMyPkg) -> Option<&MyPkg> {
+        if let Some(md5sum) = &query.md5sum {
+            return self.get(md5sum);
+        }
+        let name = query.name.as_ref()?;
+        self.complete()
+            .filter(|mypkg| &mypkg.name == name)
+            .max_by_key(|mypkg| mypkg.built_on)
+    }
+    // packages matching the name and every tag, sorted by name then newest first
+    pub fn list(&self, query: &ListMyPkg) -> ListMyPkgAck {
+        let mut matched: Vec<&MyPkg> = self
+            .complete()
+            .filter(|mypkg| query.name.as_ref().is_none_or(|name| &mypkg.name == name))
+            .filter(|mypkg| query.tags.iter().all(|tag| mypkg.tags.contains(tag)))
+            .collect();
+        matched.sort_by(|a, b| {
+            a.name
+                .cmp(&b.name)
+                .then(b.built_on.cmp(&a.built_on))
+                .then(a.md5sum.cmp(&b.md5sum))
+        });
+        let total = matched.len() as u64;
+        let limit = query.limit.unwrap_or(total);
+        ListMyPkgAck {
+            total,
+            mypkgs: matched
+                .into_iter()
+                .skip(query.offset as usize)
+                .take(limit as usize)
+                .map(|mypkg| {
+                    // piece sums are only needed to transfer a package, they would swamp a listing
+                    let mut mypkg = mypkg.clone();
+                    mypkg.files.iter_mut().for_each(|file| file.pieces.clear());
+                    mypkg
+                })
+                .collect(),
+        }
+    }
+}
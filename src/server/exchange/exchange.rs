@@ -2,7 +2,12 @@ use anyhow::{bail, Result};
 use std::collections::HashSet;
 
 use crate::{
-    protocol::{File, MyPkg, MyPkgAck, Piece, PieceAck, PieceExchange, PieceExchangeAck},
+    protocol::{
+        hash_file, Compression, Done, ErrorMsg, File, MyPkg, MyPkgAck, PieceAck, PieceExchange,
+        PieceExchangeAck, PieceWriter, MAX_RETRANSMITS,
+    },
+    server::bitfield::Bitfield,
+    server::server::{bitfield_path, file_path, quarantine_path},
     server::Connected,
     Server,
 };
@@ -17,6 +22,7 @@ pub struct Ready {
     pub peers: HashSet<String>,
     pub mypkg: MyPkg,
     pub ack: MyPkgAck,
+    pub compression: Option<Compression>,
 }
 pub struct Running {
     pub pieces: [u64; 2],
@@ -26,45 +32,182 @@ pub struct Running {
 impl ExchangeState for Ready {}
 impl ExchangeState for Running {}
 
+// how many pieces are received between syncing the file and saving its bitfield
+const SAVE_EVERY: u64 = 64;
+
 impl Exchange<Ready> {
     pub async fn exchange(mut self) -> Result<()> {
         //Result<Self, Error> {
-        for file in self.state.mypkg.clone().files {
+        let mypkg = self.state.mypkg.clone();
+        // the client only sends the files we said we were missing
+        let wanted = self.state.ack.files.clone().unwrap_or(mypkg.files.clone());
+        for file in &mypkg.files {
+            if !wanted.iter().any(|w| w.md5sum == file.md5sum) {
+                self.inner.adopt(file)?;
+            }
+        }
+        for file in wanted {
             let pe: PieceExchange = self.inner.read().await?;
-            // TODO use pe to divine if we can ask for pieces instead of all pieces
-            // we would need a cache smart enough to know bitfields and pieces received
-            // the cache on Server<Connected> would know, but it only holds HasSet<String>
-            // whihc is just md5sums.  future spot is there
-            let pa = PieceExchangeAck { pieces: None };
+            if pe.file.md5sum != file.md5sum {
+                bail!(ErrorMsg::new(
+                    ErrorMsg::UNEXPECTED,
+                    format!(
+                        "peer offered file {} but {} was expected",
+                        pe.file.md5sum, file.md5sum
+                    )
+                ));
+            }
+            // the piece geometry comes from the manifest, not from what the peer offers
+            let piece_count = file.clone().chunk_count() as u64;
+            let [start, end] = pe.pieces;
+            if start > end || end > piece_count || !file.has_valid_pieces() {
+                bail!(ErrorMsg::new(
+                    ErrorMsg::UNEXPECTED,
+                    format!(
+                        "pieces {}:{} do not fit file {} of {} pieces",
+                        start, end, file.md5sum, piece_count
+                    )
+                ));
+            }
+            // pieces received by an earlier connection are kept in a bitfield, only ask for the rest
+            let mut bitfield = Bitfield::load(&bitfield_path(&file), piece_count)?;
+            let mut missing = bitfield.missing(pe.pieces);
+            let pa = PieceExchangeAck {
+                pieces: if bitfield.is_empty() {
+                    None
+                } else {
+                    Some(missing.clone())
+                },
+            };
             self.inner.write(pa).await?;
 
-            let [start, end] = pe.pieces;
-            self.receive([start, end], file).await?;
+            // an empty file has no pieces to receive but still has to exist
+            if piece_count == 0 {
+                let path = file_path(&file);
+                if let Some(parent) = std::path::Path::new(&path).parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::File::create(path)?;
+            }
+            // pieces that fail their sum are left out of the bitfield and asked for again
+            let mut rounds = 0;
+            while !missing.is_empty() {
+                if rounds > MAX_RETRANSMITS {
+                    bail!(ErrorMsg::new(
+                        ErrorMsg::CORRUPT,
+                        format!(
+                            "file {} still misses {:?} after {} rounds",
+                            file.md5sum, missing, rounds
+                        )
+                    ));
+                }
+                self.receive(missing, &mut bitfield, file.clone()).await?;
+                missing = bitfield.missing(pe.pieces);
+                let pa = PieceExchangeAck {
+                    pieces: Some(missing.clone()),
+                };
+                self.inner.write(pa).await?;
+                rounds += 1;
+            }
+            let verified = verify(file.clone()).await;
+            Bitfield::remove(&bitfield_path(&file))?;
+            verified?;
+        }
+        // the package only counts as held once the client says it is done and every file verified
+        let done: Done = self.inner.read().await?;
+        if done.md5sum != self.state.mypkg.md5sum {
+            bail!(ErrorMsg::new(
+                ErrorMsg::UNEXPECTED,
+                format!(
+                    "peer finished {} but {} was offered",
+                    done.md5sum, self.state.mypkg.md5sum
+                )
+            ));
         }
+        self.inner.set(self.state.mypkg.clone())?;
+        // echo done so the client knows the package is committed
+        self.inner.write(done).await?;
         Ok(())
     }
-    async fn receive(&mut self, pieces: [u64; 2], file: File) -> Result<()> {
+    // receive the missing pieces, the bitfield is saved even if the peer goes away half way
+    async fn receive(
+        &mut self,
+        missing: Vec<[u64; 2]>,
+        bitfield: &mut Bitfield,
+        file: File,
+    ) -> Result<()> {
+        let filename = file_path(&file);
+        eprintln!("receiving {:?} for file {}", &missing, &filename);
+        let mut write_at = file.clone().write_at(filename.clone())?;
+        let mut result = Ok(());
+        for pieces in missing {
+            result = self
+                .receive_range(pieces, &mut write_at, bitfield, &file)
+                .await;
+            if result.is_err() {
+                break;
+            }
+        }
+        if result.is_ok() {
+            // the last piece may be short and an earlier attempt may have left a longer file
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(&filename)?
+                .set_len(file.length)?;
+        }
+        bitfield.save(&bitfield_path(&file), &filename)?;
+        result
+    }
+    async fn receive_range(
+        &mut self,
+        pieces: [u64; 2],
+        write_at: &mut PieceWriter,
+        bitfield: &mut Bitfield,
+        file: &File,
+    ) -> Result<()> {
         let mut contigious = 0;
         let [start, end] = pieces;
-        let filename = format!("data/{}/{}", &file.md5sum, file.filename());
-        println!("looping from {};{} for file {}", start, end, &filename);
-        let mut write_at = file.write_at(filename)?;
         for _i in start..end {
-            let p: Piece = self.inner.read().await?;
-            if p.piece < start || p.piece > end {
-                bail!(
-                    "piece is out of bounds {} is not within {}:{}",
-                    p.piece,
-                    start,
-                    end
+            let p = self.inner.read_piece().await?;
+            if p.piece < start || p.piece >= end {
+                bail!(ErrorMsg::new(
+                    ErrorMsg::UNEXPECTED,
+                    format!(
+                        "piece is out of bounds {} is not within {}:{}",
+                        p.piece, start, end
+                    )
+                ));
+            }
+            let len = file.piece_len(p.piece);
+            let p = p.decompress(self.state.compression, len)?;
+            if p.data.len() != file.piece_len(p.piece) {
+                bail!(ErrorMsg::new(
+                    ErrorMsg::UNEXPECTED,
+                    format!(
+                        "piece {} is {} bytes but should be {}",
+                        p.piece,
+                        p.data.len(),
+                        file.piece_len(p.piece)
+                    )
+                ));
+            }
+            if file.verify_piece(p.piece, &p.data) {
+                write_at(p.piece, &p.data)?;
+                bitfield.set(p.piece);
+                if (p.piece + 1).is_multiple_of(SAVE_EVERY) {
+                    bitfield.save(&bitfield_path(file), &file_path(file))?;
+                }
+            } else {
+                eprintln!(
+                    "piece {} of {} is corrupt, asking again",
+                    p.piece, file.md5sum
                 );
             }
-            write_at(p.piece, p.data.as_slice())?;
             if p.piece == 0 || p.piece - 1 == contigious {
                 contigious = p.piece;
             }
             if let Some(ack) = p.ack {
-                println!("ack requested for {} sending {}", &ack, &contigious);
+                eprintln!("ack requested for {} sending {}", &ack, &contigious);
                 // TODO enable again
                 // if *p.Ack != contiguousPiece {
                 //     delta := contiguousPiece - *p.Ack
@@ -77,3 +220,32 @@ impl Exchange<Ready> {
         Ok(())
     }
 }
+
+// hash a received file against its manifest, a file that does not match is moved to quarantine
+async fn verify(file: File) -> Result<()> {
+    let path = file_path(&file);
+    let written = {
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || hash_file(&path, file.digest)).await??
+    };
+    if written.md5sum == file.md5sum && written.length == file.length {
+        return Ok(());
+    }
+    let quarantined = quarantine_path(&file);
+    if let Some(parent) = std::path::Path::new(&quarantined).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::rename(&path, &quarantined)?;
+    eprintln!("moved {} to {}", path, quarantined);
+    bail!(ErrorMsg::new(
+        ErrorMsg::CORRUPT,
+        format!(
+            "file {} failed verification, expected {} with {} bytes got {} with {} bytes",
+            file.filename(),
+            file.md5sum,
+            file.length,
+            written.md5sum,
+            written.length
+        )
+    ))
+}
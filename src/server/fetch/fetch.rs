@@ -0,0 +1,105 @@
+use anyhow::{bail, Error, Result};
+
+use crate::{
+    protocol::{
+        Compression, ErrorMsg, FetchMyPkg, FetchMyPkgAck, File, MyPkg, Piece, PieceExchange,
+        PieceExchangeAck, MAX_RETRANSMITS,
+    },
+    server::server::file_path,
+    server::Connected,
+    Server,
+};
+
+pub struct Fetch<S: FetchState> {
+    inner: Server<Connected>,
+    state: S,
+}
+
+pub trait FetchState {}
+pub struct FetchMsg;
+pub struct Sending {
+    mypkg: Option<MyPkg>,
+    compression: Option<Compression>,
+}
+
+impl FetchState for FetchMsg {}
+impl FetchState for Sending {}
+
+impl Fetch<FetchMsg> {
+    pub fn new(server: Server<Connected>) -> Fetch<FetchMsg> {
+        Fetch {
+            inner: server,
+            state: FetchMsg,
+        }
+    }
+    pub async fn wait_for_fetch(mut self) -> Result<Fetch<Sending>, Error> {
+        let fetch: FetchMyPkg = self.inner.read().await?;
+        self.lookup(fetch).await
+    }
+    // answer a fetch which has already been read off the wire
+    pub async fn lookup(mut self, fetch: FetchMyPkg) -> Result<Fetch<Sending>, Error> {
+        dbg!(&fetch);
+        let mypkg = self.inner.lookup(&fetch);
+        let compression = Compression::choose(&fetch.compression);
+        let ack = FetchMyPkgAck {
+            mypkg: mypkg.clone(),
+            compression,
+        };
+        self.inner.write(ack).await?;
+        Ok(Fetch {
+            inner: self.inner,
+            state: Sending { mypkg, compression },
+        })
+    }
+}
+
+impl Fetch<Sending> {
+    pub async fn send(mut self) -> Result<(), Error> {
+        let Some(mypkg) = self.state.mypkg.take() else {
+            eprintln!("nothing to send, package is unknown");
+            return Ok(());
+        };
+        for file in mypkg.files {
+            let piece_count = file.clone().chunk_count();
+            let pe = PieceExchange {
+                pieces: [0, piece_count as u64],
+                file: file.clone(),
+            };
+            self.inner.write(pe).await?;
+            let pa: PieceExchangeAck = self.inner.read().await?;
+            dbg!(&pa);
+            let wanted = pa.pieces.unwrap_or(vec![[0, piece_count as u64]]);
+            self.send_file(wanted, file).await?;
+        }
+        Ok(())
+    }
+    // send the wanted pieces, then whatever the client reports as corrupt, until it has them all
+    async fn send_file(&mut self, mut wanted: Vec<[u64; 2]>, file: File) -> Result<(), Error> {
+        let stored = File {
+            path: file_path(&file),
+            ..file
+        };
+        eprintln!("send file {} pieces {:?}", &stored.path, &wanted);
+        let read_at = stored.clone().read_at()?;
+        let mut rounds = 0;
+        while !wanted.is_empty() {
+            if rounds > MAX_RETRANSMITS {
+                bail!(ErrorMsg::new(
+                    ErrorMsg::CORRUPT,
+                    format!("client still misses {:?} after {} rounds", wanted, rounds)
+                ));
+            }
+            for [start, end] in wanted {
+                for piece in start..end {
+                    let p =
+                        Piece::read(&read_at, &stored, piece)?.compress(self.state.compression)?;
+                    self.inner.write(p).await?;
+                }
+            }
+            let pa: PieceExchangeAck = self.inner.read().await?;
+            wanted = pa.pieces.unwrap_or_default();
+            rounds += 1;
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,11 @@
+pub mod bitfield;
+pub mod catalog;
 pub mod exchange;
+pub mod fetch;
 pub mod offer;
 pub mod server;
 
+pub use catalog::Catalog;
+pub use fetch::fetch::Fetch;
 pub use offer::offer::Offer;
 pub use server::Connected;
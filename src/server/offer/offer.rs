@@ -1,7 +1,7 @@
 use std::{borrow::BorrowMut, collections::HashSet};
 
 use crate::{
-    protocol::{MyPkg, MyPkgAck, NegotiateMyPkg, NegotiateMyPkgAck},
+    protocol::{Compression, ErrorMsg, MyPkg, MyPkgAck, NegotiateMyPkg, NegotiateMyPkgAck},
     server::exchange::{Exchange, Ready},
     server::Connected,
     Server,
@@ -44,21 +44,35 @@ impl Offer<OfferMsg> {
     }
     pub async fn wait_for_mypkg(mut self) -> Result<Offer<Negotiate>, Error> {
         let mypkg: MyPkg = self.borrow_mut().inner.read().await?;
+        self.accept(mypkg).await
+    }
+    // answer an offer for a mypkg which has already been read off the wire
+    pub async fn accept(mut self, mypkg: MyPkg) -> Result<Offer<Negotiate>, Error> {
         dbg!(&mypkg);
+        mypkg
+            .check_digest()
+            .map_err(|e| ErrorMsg::new(ErrorMsg::MALFORMED, e.to_string()))?;
         let accept: MyPkgAck;
         if let Some(_md5sum) = self.inner.get(mypkg.md5sum.to_owned()) {
-            println!("cache hit {}", &mypkg.md5sum);
+            eprintln!("cache hit {}", &mypkg.md5sum);
             accept = MyPkgAck {
                 md5sum: Some(mypkg.md5sum.to_owned()),
                 files: Some(vec![]),
             };
             self.borrow_mut().inner.write(accept.clone()).await?;
         } else {
-            self.inner.set(mypkg.md5sum.to_owned());
-            println!("cache miss {}", &mypkg.md5sum);
+            self.inner.set_partial(mypkg.clone())?;
+            // files shared with packages we already hold don't need to be sent again
+            let missing = self.inner.missing(&mypkg);
+            eprintln!(
+                "cache miss {}, {} of {} files missing",
+                &mypkg.md5sum,
+                missing.len(),
+                mypkg.files.len()
+            );
             accept = MyPkgAck {
                 md5sum: Some(mypkg.md5sum.to_owned()),
-                files: None,
+                files: Some(missing),
             };
             self.borrow_mut().inner.write(accept.clone()).await?;
         }
@@ -82,9 +96,12 @@ impl Offer<Negotiate> {
     pub async fn negotiate(mut self) -> Result<Exchange<Ready>, Error> {
         let neg_msg: NegotiateMyPkg = self.borrow_mut().inner.read().await?;
         dbg!(&neg_msg);
+        // pieces are compressed with the first compression the client offered that we know
+        let compression = Compression::choose(&neg_msg.compression);
         let neg_ack_msg = NegotiateMyPkgAck {
             md5sum: neg_msg.md5sum,
             peers: Some(self.peers()),
+            compression,
         };
         self.borrow_mut().inner.write(neg_ack_msg).await?;
         Ok(Exchange {
@@ -93,6 +110,7 @@ impl Offer<Negotiate> {
                 peers: self.state.peers,
                 mypkg: self.state.mypkg,
                 ack: self.state.ack,
+                compression,
             },
         })
     }
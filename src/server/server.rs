@@ -1,21 +1,45 @@
 use crate::{
     protocol::{
-        MessageType, ToMessageType, BLOCK_SIZE, BLOCK_SIZE_LESS_HEADER, HEADER_SIZE, MSG_SIZE,
+        Codec, ErrorMsg, FetchMyPkg, File, Hello, HelloAck, ListMyPkg, ListMyPkgAck, MessageType,
+        MyPkg, Piece, ReadResult, ToMessageType,
     },
-    server::Offer,
+    server::{catalog::PkgState, Catalog, Fetch, Offer},
+    tls::ServerTls,
+    transport::{Listener, Stream},
 };
 use anyhow::{bail, Error, Result};
+use futures::{SinkExt, StreamExt};
 use serde::de;
 use std::collections::HashSet;
+use std::io;
 use std::sync::{Arc, RwLock};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    io::{AsyncWriteExt, ReadHalf, WriteHalf},
+    sync::Mutex,
 };
+use tokio_util::codec::{FramedRead, FramedWrite};
 use tokio_util::sync::CancellationToken;
 use tokio_util::task::TaskTracker;
 
-pub struct ServerConnection(TcpListener);
+// where a received file lives, files are keyed by their own md5sum so packages can share them
+pub fn file_path(file: &File) -> String {
+    format!("data/{}/{}", &file.md5sum, file.filename())
+}
+
+// where the bitfield of a partially received file is saved between connections
+pub fn bitfield_path(file: &File) -> String {
+    format!("data/bitfield/{}/{}", &file.md5sum, file.filename())
+}
+
+// where a received file that failed verification is kept for inspection
+pub fn quarantine_path(file: &File) -> String {
+    format!("data/quarantine/{}/{}", &file.md5sum, file.filename())
+}
+
+// where the package index keeps one record per package
+pub const INDEX_DIR: &str = "data/index";
+
+pub struct ServerConnection(Box<dyn Listener>);
 pub struct Server<S: ServerState> {
     // extra is a generic field for use within different states to squirrel data
     state: S,
@@ -25,12 +49,18 @@ pub trait ServerState {}
 pub struct Initial;
 pub struct Listening {
     listener: ServerConnection,
-    cache: Arc<RwLock<HashSet<String>>>,
+    catalog: Arc<RwLock<Catalog>>,
     peers: HashSet<String>,
+    // connections are used as they are accepted unless tls is configured
+    tls: Option<ServerTls>,
 }
 pub struct Connected {
-    socket: TcpStream,
-    cache: Arc<RwLock<HashSet<String>>>,
+    reader: FramedRead<ReadHalf<Stream>, Codec>,
+    // shared with the reporter, which tells the client why a session failed
+    writer: Arc<Mutex<FramedWrite<WriteHalf<Stream>, Codec>>>,
+    catalog: Arc<RwLock<Catalog>>,
+    // the optional features both sides support, known once the client said hello
+    features: Vec<String>,
 }
 pub struct Disconnected;
 
@@ -46,19 +76,32 @@ impl Server<Initial> {
     //         state: Connected,
     //     })
     // }
-    pub async fn new(listener: TcpListener, peers: HashSet<String>) -> Result<Server<Listening>> {
+    pub async fn new(
+        listener: impl Listener + 'static,
+        peers: HashSet<String>,
+    ) -> Result<Server<Listening>> {
         Ok(Server {
             state: Listening {
-                listener: ServerConnection(listener),
-                cache: Arc::new(RwLock::new(HashSet::new())),
+                listener: ServerConnection(Box::new(listener)),
+                catalog: Arc::new(RwLock::new(Catalog::open(INDEX_DIR)?)),
                 peers,
+                tls: None,
             },
         })
     }
+    // serve a single session over a stream that is already open, such as stdin and stdout
+    pub async fn serve_stream(stream: Stream, peers: HashSet<String>) -> Result<()> {
+        let catalog = Arc::new(RwLock::new(Catalog::open(INDEX_DIR)?));
+        Server::session(stream, catalog, peers).await
+    }
 }
 impl Server<Listening> {
+    // require every connection to speak tls
+    pub fn tls(mut self, tls: ServerTls) -> Self {
+        self.state.tls = Some(tls);
+        self
+    }
     pub async fn serve(&self, ctx: CancellationToken) -> Result<(), Error> {
-
         let tracker = TaskTracker::new();
         loop {
             tokio::select! {
@@ -69,17 +112,24 @@ impl Server<Listening> {
                 }
                 event = self.state.listener.0.accept() => {
                     match event {
-                        Ok((socket, _)) => {
+                        Ok(stream) => {
                             let peers = self.state.peers.clone();
-                            let cache = self.state.cache.clone();
-                            let conn = Server {
-                                state: Connected { socket, cache },
-                            };
+                            let catalog = self.state.catalog.clone();
+                            let tls = self.state.tls.clone();
                             // Spawn a new task to handle the connection
                             tracker.spawn(async move {
-                                if let Err(e) = conn.wait_for_offer(peers).await {
-                                    eprintln!("Failed to handle connection: {:?}", e);
-                                }
+                                // the handshake runs in the task so a slow client can't hold up accept
+                                let stream = match tls {
+                                    Some(tls) => match tls.accept(stream).await {
+                                        Ok(stream) => stream,
+                                        Err(e) => {
+                                            eprintln!("Failed tls handshake: {:?}", e);
+                                            return;
+                                        }
+                                    },
+                                    None => stream,
+                                };
+                                let _ = Server::session(stream, catalog, peers).await;
                             });
                         },
                         Err(e) => {dbg!(e);}
@@ -90,17 +140,71 @@ impl Server<Listening> {
     }
 }
 
-#[derive(Debug)]
-pub struct ReadResult {
-    pub message_type: u16,
-    pub raw_msg: Vec<u8>,
+// the write half of a connection kept aside to report a failed session, whatever state it was in
+pub struct Reporter(Arc<Mutex<FramedWrite<WriteHalf<Stream>, Codec>>>);
+
+impl Reporter {
+    pub async fn report(&self, e: &Error) {
+        let Some(msg) = ErrorMsg::from_error(e) else {
+            return;
+        };
+        // the client may be gone already, there is no one left to tell then
+        let _ = self.0.lock().await.send(&msg.to_message_type()).await;
+    }
 }
 
 impl Server<Connected> {
-    pub async fn wait_for_offer(self, peers: HashSet<String>) -> Result<()> {
+    fn connected(stream: Stream, catalog: Arc<RwLock<Catalog>>) -> (Self, Reporter) {
+        let (reader, writer) = tokio::io::split(stream);
+        let writer = Arc::new(Mutex::new(FramedWrite::new(writer, Codec::default())));
+        let conn = Server {
+            state: Connected {
+                reader: FramedRead::new(reader, Codec::default()),
+                writer: writer.clone(),
+                catalog,
+                features: vec![],
+            },
+        };
+        (conn, Reporter(writer))
+    }
+    // handle a session, a client whose session failed is told why
+    async fn session(
+        stream: Stream,
+        catalog: Arc<RwLock<Catalog>>,
+        peers: HashSet<String>,
+    ) -> Result<()> {
+        let (conn, reporter) = Server::connected(stream, catalog);
+        if let Err(e) = conn.handle(peers).await {
+            eprintln!("Failed to handle connection: {:?}", e);
+            reporter.report(&e).await;
+            return Err(e);
+        }
+        Ok(())
+    }
+    // the client says hello first, its next message decides if it is uploading or downloading
+    pub async fn handle(mut self, peers: HashSet<String>) -> Result<()> {
+        self.hello().await?;
+        let r = self.read_message_type().await?;
+        match MessageType::deserialize(r.message_type, &r.raw_msg)? {
+            MessageType::MyPkg(mypkg) => self.wait_for_offer(mypkg, peers).await,
+            MessageType::FetchMyPkg(fetch) => {
+                Fetch::new(self).lookup(fetch).await?.send().await?;
+                Ok(())
+            }
+            MessageType::ListMyPkg(query) => {
+                let ack = self.list(&query);
+                self.write(ack).await
+            }
+            _ => bail!(ErrorMsg::new(
+                ErrorMsg::UNEXPECTED,
+                format!("unexpected first message type {}", r.message_type)
+            )),
+        }
+    }
+    pub async fn wait_for_offer(self, mypkg: MyPkg, peers: HashSet<String>) -> Result<()> {
         let state = Offer::new(self);
         state
-            .wait_for_mypkg()
+            .accept(mypkg)
             .await?
             .add_peers(peers)
             .negotiate()
@@ -113,80 +217,134 @@ impl Server<Connected> {
         // tricky, how do i do multiple files? i need a loop or something
         Ok(())
     }
-    pub fn get(&self, v: String) -> Option<String> {
-        if let Some(v) = self.state.cache.read().unwrap().get(&v) {
-            return Some(v.to_owned());
+    // answer the client's hello, a client speaking an incompatible version is refused
+    pub async fn hello(&mut self) -> Result<()> {
+        let r = self.read_message_type().await?;
+        let MessageType::Hello(peer) = MessageType::deserialize(r.message_type, &r.raw_msg)? else {
+            bail!(ErrorMsg::new(
+                ErrorMsg::INCOMPATIBLE,
+                format!(
+                    "expected hello but got message type {}, the client predates protocol versions",
+                    r.message_type
+                )
+            ));
+        };
+        let hello = Hello::new();
+        if let Err(e) = hello.check_version(peer.version, peer.min_version) {
+            // still tell the client what we speak so it can explain the refusal
+            let refusal = HelloAck {
+                version: hello.version,
+                min_version: hello.min_version,
+                message_types: hello.message_types,
+                features: vec![],
+            };
+            self.write(refusal).await?;
+            return Err(e);
         }
-        None
+        let ack = hello.ack(&peer);
+        self.state.features = ack.features.clone();
+        self.write(ack).await
+    }
+    pub fn features(&self) -> &[String] {
+        &self.state.features
     }
-    pub fn set(&mut self, v: String) -> bool {
-        self.state.cache.write().unwrap().insert(v)
+    pub fn get(&self, v: String) -> Option<MyPkg> {
+        self.state.catalog.read().unwrap().get(&v).cloned()
+    }
+    // record mypkg as held by this server, returns true if it was not held before
+    pub fn set(&mut self, v: MyPkg) -> Result<bool> {
+        self.state
+            .catalog
+            .write()
+            .unwrap()
+            .set(v, PkgState::Complete)
+    }
+    // record mypkg as being received, it is not served until set once verified
+    pub fn set_partial(&mut self, v: MyPkg) -> Result<()> {
+        self.state
+            .catalog
+            .write()
+            .unwrap()
+            .set(v, PkgState::Partial)?;
+        Ok(())
+    }
+    // the files of mypkg that are not stored yet as part of any package
+    pub fn missing(&self, mypkg: &MyPkg) -> Vec<File> {
+        let catalog = self.state.catalog.read().unwrap();
+        mypkg
+            .files
+            .iter()
+            .filter(|file| catalog.file(&file.md5sum).is_none())
+            .cloned()
+            .collect()
+    }
+    // make a file stored by another package available under this file's name
+    pub fn adopt(&self, file: &File) -> Result<()> {
+        let Some(stored) = self
+            .state
+            .catalog
+            .read()
+            .unwrap()
+            .file(&file.md5sum)
+            .cloned()
+        else {
+            bail!("no stored file has md5sum {}", file.md5sum);
+        };
+        let (from, to) = (file_path(&stored), file_path(file));
+        if from == to {
+            return Ok(());
+        }
+        if std::fs::metadata(&to).map(|m| m.len()).ok() == Some(file.length) {
+            return Ok(());
+        }
+        if std::fs::hard_link(&from, &to).is_err() {
+            std::fs::copy(&from, &to)?;
+        }
+        Ok(())
+    }
+    pub fn lookup(&self, query: &FetchMyPkg) -> Option<MyPkg> {
+        self.state.catalog.read().unwrap().lookup(query).cloned()
+    }
+    pub fn list(&self, query: &ListMyPkg) -> ListMyPkgAck {
+        self.state.catalog.read().unwrap().list(query)
     }
     pub async fn close(&mut self) -> Result<()> {
         self.state
-            .socket
+            .writer
+            .lock()
+            .await
+            .get_mut()
             .shutdown()
             .await
             .map_err(anyhow::Error::from)
     }
     pub async fn write_message_type(&mut self, t: &MessageType) -> Result<()> {
-        let b = t.serialize_inner()?;
-        let message_type = t.message_type();
-        let mut buf = [0; BLOCK_SIZE];
-        for chunk in b.chunks(BLOCK_SIZE_LESS_HEADER) {
-            let length = chunk.len() as u16;
-            buf[0..MSG_SIZE].copy_from_slice(&length.to_be_bytes());
-            buf[MSG_SIZE..HEADER_SIZE].copy_from_slice(&message_type.to_be_bytes());
-            buf[HEADER_SIZE..HEADER_SIZE + chunk.len()].copy_from_slice(chunk);
-            self.state
-                .socket
-                .write_all(&buf[..HEADER_SIZE + length as usize])
-                .await?;
-        }
-        Ok(())
+        self.state.writer.lock().await.send(t).await
     }
     pub async fn read_message_type(&mut self) -> Result<ReadResult> {
-        let mut buf = [0; BLOCK_SIZE];
-        let mut raw_msg = vec![];
-        loop {
-            self.state
-                .socket
-                .read_exact(&mut buf[..HEADER_SIZE])
-                .await?;
-            let prefix_length = u16::from_be_bytes([buf[0], buf[1]]) as usize;
-            if prefix_length > BLOCK_SIZE_LESS_HEADER {
-                bail!("invalid frame length {}", prefix_length);
-            }
-            let message_type = u16::from_be_bytes([buf[2], buf[3]]);
-            if !MessageType::is_valid_message_type(message_type) {
-                bail!("invalid message type {}", message_type);
-            }
-            let n = self
-                .state
-                .socket
-                .read_exact(&mut buf[HEADER_SIZE..HEADER_SIZE + prefix_length])
-                .await?;
-
-            if n > 0 {
-                raw_msg.extend_from_slice(&buf[HEADER_SIZE..HEADER_SIZE + n]);
-            }
-            if prefix_length < BLOCK_SIZE_LESS_HEADER {
-                // return MessageType::deserialize(message_type, &raw_msg);
-                return Ok(ReadResult {
-                    message_type,
-                    raw_msg,
-                });
-            }
+        match self.state.reader.next().await {
+            Some(r) => r,
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
         }
     }
     pub async fn read<T: de::DeserializeOwned>(&mut self) -> Result<T> {
         let r = self.read_message_type().await?;
-        let msg: T = serde_bencode::from_bytes(&r.raw_msg).map_err(|e| {
-            println!("we detected a message type of {}, but got an error saying {}. are you asking for the correct message type?", r.message_type, &e);
-            e
+        let msg: T = serde_bencode::from_bytes(&r.raw_msg).inspect_err(|e| {
+            eprintln!("we detected a message type of {}, but got an error saying {}. are you asking for the correct message type?", r.message_type, e);
         })?;
         Ok(msg)
     }
+    // pieces come in their own binary frame, not bencoded
+    pub async fn read_piece(&mut self) -> Result<Piece> {
+        let r = self.read_message_type().await?;
+        if r.message_type != MessageType::PIECE {
+            bail!(ErrorMsg::new(
+                ErrorMsg::UNEXPECTED,
+                format!("expected a piece but got message type {}", r.message_type)
+            ));
+        }
+        Piece::decode(r.raw_msg)
+    }
     pub async fn write<T: ToMessageType>(&mut self, t: T) -> Result<()> {
         self.write_message_type(&t.to_message_type()).await
     }
@@ -0,0 +1,38 @@
+use clap::Parser;
+
+use crate::tls::ServerTls;
+use anyhow::{bail, Result};
+
+/// Serve packages to blobfish clients
+#[derive(Parser, Debug)]
+#[command(name = "server")]
+pub struct ServerArgs {
+    /// A tcp address, or unix:<path> to listen on a unix socket
+    #[arg(short, long, default_value = "127.0.0.1:8080")]
+    pub listen: String,
+    /// Serve a single session over stdin and stdout instead of listening, for use over ssh
+    #[arg(long, conflicts_with_all = ["listen", "cert", "key", "client_ca"])]
+    pub stdio: bool,
+    /// Serve over TLS with this certificate
+    #[arg(long, value_name = "PEM", requires = "key")]
+    pub cert: Option<String>,
+    /// The key of the server certificate
+    #[arg(long, value_name = "PEM", requires = "cert")]
+    pub key: Option<String>,
+    /// Only accept clients presenting a certificate signed by this CA
+    #[arg(long, value_name = "PEM")]
+    pub client_ca: Option<String>,
+}
+
+impl ServerArgs {
+    // the tls settings to serve with, None serves plain tcp
+    pub fn tls(&self) -> Result<Option<ServerTls>> {
+        let (Some(cert), Some(key)) = (&self.cert, &self.key) else {
+            if self.client_ca.is_some() {
+                bail!("--client-ca needs --cert and --key, client certificates are only asked for over tls");
+            }
+            return Ok(None);
+        };
+        Ok(Some(ServerTls::load(cert, key, self.client_ca.as_deref())?))
+    }
+}
@@ -0,0 +1,116 @@
+use anyhow::{bail, Error, Result};
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, RootCertStore, ServerConfig,
+};
+use std::io;
+use std::sync::Arc;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::transport::Stream;
+
+// the server side of tls, optionally asking clients for a certificate signed by client_ca
+#[derive(Clone)]
+pub struct ServerTls(TlsAcceptor);
+
+impl ServerTls {
+    pub fn new(cert_pem: &[u8], key_pem: &[u8], client_ca_pem: Option<&[u8]>) -> Result<ServerTls> {
+        let provider = provider();
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match client_ca_pem {
+            Some(pem) => {
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots(pem)?), provider)
+                        .build()?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder.with_single_cert(certs(cert_pem)?, key(key_pem)?)?;
+        Ok(ServerTls(TlsAcceptor::from(Arc::new(config))))
+    }
+    pub fn load(cert: &str, key: &str, client_ca: Option<&str>) -> Result<ServerTls> {
+        let client_ca = client_ca.map(read).transpose()?;
+        ServerTls::new(&read(cert)?, &read(key)?, client_ca.as_deref())
+    }
+    // wrap an accepted connection, whatever carries it
+    pub async fn accept(&self, stream: Stream) -> io::Result<Stream> {
+        Ok(Box::new(self.0.accept(stream).await?))
+    }
+}
+
+// the client side of tls, the server has to present a certificate for server_name signed by ca
+#[derive(Clone)]
+pub struct ClientTls {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl ClientTls {
+    // identity is the certificate and key to present to servers that ask for one
+    pub fn new(
+        ca_pem: &[u8],
+        server_name: &str,
+        identity: Option<(&[u8], &[u8])>,
+    ) -> Result<ClientTls> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots(ca_pem)?);
+        let config = match identity {
+            Some((cert_pem, key_pem)) => {
+                builder.with_client_auth_cert(certs(cert_pem)?, key(key_pem)?)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        Ok(ClientTls {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name: ServerName::try_from(server_name.to_string())?,
+        })
+    }
+    pub fn load(ca: &str, server_name: &str, identity: Option<(&str, &str)>) -> Result<ClientTls> {
+        let identity = match identity {
+            Some((cert, key)) => Some((read(cert)?, read(key)?)),
+            None => None,
+        };
+        let identity = identity.as_ref().map(|(c, k)| (c.as_slice(), k.as_slice()));
+        ClientTls::new(&read(ca)?, server_name, identity)
+    }
+    pub async fn connect(&self, stream: Stream) -> io::Result<Stream> {
+        let server_name = self.server_name.clone();
+        Ok(Box::new(self.connector.connect(server_name, stream).await?))
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn read(path: &str) -> Result<Vec<u8>> {
+    std::fs::read(path).map_err(|e| Error::new(e).context(format!("reading {}", path)))
+}
+
+fn certs(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut &pem[..]).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        bail!("no certificate found in pem");
+    }
+    Ok(certs)
+}
+
+fn key(pem: &[u8]) -> Result<PrivateKeyDer<'static>> {
+    match rustls_pemfile::private_key(&mut &pem[..])? {
+        Some(key) => Ok(key),
+        None => bail!("no private key found in pem"),
+    }
+}
+
+fn roots(pem: &[u8]) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(pem)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
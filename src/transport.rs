@@ -0,0 +1,188 @@
+use futures::future::BoxFuture;
+use std::io;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::process::Command;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::protocol::BLOCK_SIZE;
+
+// anything a session can run over, tcp, a unix socket, tls or an in memory pipe
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+// the connection as the protocol sees it, the typestates don't care what carries it
+pub type Stream = Box<dyn Io>;
+
+// where a server takes its connections from
+pub trait Listener: Send + Sync {
+    fn accept(&self) -> BoxFuture<'_, io::Result<Stream>>;
+}
+
+impl Listener for Box<dyn Listener> {
+    fn accept(&self) -> BoxFuture<'_, io::Result<Stream>> {
+        self.as_ref().accept()
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<Stream>> {
+        Box::pin(async move {
+            let (socket, _) = TcpListener::accept(self).await?;
+            Ok(Box::new(socket) as Stream)
+        })
+    }
+}
+
+impl Listener for UnixListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<Stream>> {
+        Box::pin(async move {
+            let (socket, _) = UnixListener::accept(self).await?;
+            Ok(Box::new(socket) as Stream)
+        })
+    }
+}
+
+// addresses starting with unix: are unix socket paths, anything else is a tcp address
+pub async fn connect(addr: &str) -> io::Result<Stream> {
+    match addr.strip_prefix("unix:") {
+        Some(path) => Ok(Box::new(UnixStream::connect(path).await?)),
+        None => Ok(Box::new(TcpStream::connect(addr).await?)),
+    }
+}
+
+pub async fn bind(addr: &str) -> io::Result<Box<dyn Listener>> {
+    match addr.strip_prefix("unix:") {
+        Some(path) => Ok(Box::new(UnixListener::bind(path)?)),
+        None => Ok(Box::new(TcpListener::bind(addr).await?)),
+    }
+}
+
+// an in memory listener, every connect hands it one end of a fresh duplex pipe
+pub fn pipe() -> (PipeListener, PipeConnector) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    (PipeListener(Mutex::new(rx)), PipeConnector(tx))
+}
+
+pub struct PipeListener(Mutex<mpsc::UnboundedReceiver<DuplexStream>>);
+
+#[derive(Clone)]
+pub struct PipeConnector(mpsc::UnboundedSender<DuplexStream>);
+
+impl Listener for PipeListener {
+    fn accept(&self) -> BoxFuture<'_, io::Result<Stream>> {
+        Box::pin(async move {
+            match self.0.lock().await.recv().await {
+                Some(stream) => Ok(Box::new(stream) as Stream),
+                // no one can connect anymore, wait for the server to be cancelled
+                None => std::future::pending().await,
+            }
+        })
+    }
+}
+
+impl PipeConnector {
+    pub fn connect(&self) -> io::Result<Stream> {
+        let (client, server) = tokio::io::duplex(4 * BLOCK_SIZE);
+        self.0
+            .send(server)
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        Ok(Box::new(client))
+    }
+}
+
+// a reader and a writer used together as one stream, such as stdin and stdout.
+// shutting it down drops the writer, pipes only signal the end once they are closed
+pub struct Joined<R, W> {
+    reader: R,
+    writer: Option<W>,
+}
+
+impl<R: AsyncRead + Unpin, W: Unpin> AsyncRead for Joined<R, W> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().reader).poll_read(cx, buf)
+    }
+}
+
+impl<R: Unpin, W: AsyncWrite + Unpin> AsyncWrite for Joined<R, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().writer {
+            Some(writer) => Pin::new(writer).poll_write(cx, buf),
+            None => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().writer {
+            Some(writer) => Pin::new(writer).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(writer) = &mut this.writer {
+            ready!(Pin::new(writer).poll_shutdown(cx))?;
+        }
+        this.writer = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+// our own stdin and stdout, for a server started by ssh or another client
+pub fn stdio() -> Stream {
+    Box::new(Joined {
+        reader: tokio::io::stdin(),
+        writer: Some(tokio::io::stdout()),
+    })
+}
+
+// run command through the shell and talk over its stdin and stdout, its stderr is left to ours.
+// closing the stream closes its stdin, which ends a well behaved command
+pub fn spawn(command: &str) -> io::Result<Stream> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let (Some(writer), Some(reader)) = (child.stdin.take(), child.stdout.take()) else {
+        return Err(io::Error::other("spawned command has no pipes"));
+    };
+    Ok(Box::new(Joined {
+        reader,
+        writer: Some(writer),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_spawn() -> io::Result<()> {
+        let mut stream = spawn("cat")?;
+        stream.write_all(b"blobfish").await?;
+        let mut buf = [0; 8];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"blobfish");
+        // closing our side ends cat, and with it the stream
+        stream.shutdown().await?;
+        assert_eq!(stream.read(&mut buf).await?, 0);
+
+        // a command that goes away reads as the end of the stream
+        let mut stream = spawn("exit 3")?;
+        assert_eq!(stream.read(&mut buf).await?, 0);
+        Ok(())
+    }
+}